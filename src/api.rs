@@ -0,0 +1,234 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    server::conn::http1,
+    service::service_fn,
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde_json::json;
+use slog::{debug, error, info, Logger};
+use tokio::net::TcpListener;
+
+use crate::{
+    command::{BotCommand, CommandHandler, Origin},
+    config::{BotConfig, ConfigMonitor},
+    irc::extract_urls,
+    omdb,
+    registry::{self, Action},
+};
+
+/// Largest request body we'll read
+const MAX_BODY_BYTES: usize = 8 * 1024;
+
+/// What to look up, e.g. `{"url": "https://example.com/"}` or
+/// `{"command": ".imdb the matrix"}`
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+enum LookupRequest {
+    Url(String),
+    Command(String),
+}
+
+/// Answer `POST /lookup` requests with the same results we'd show on IRC, as
+/// JSON
+pub async fn serve(
+    log: Logger,
+    listener: TcpListener,
+    config: ConfigMonitor,
+    handler: CommandHandler,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(log, "api"; "status" => "accept failed", "error" => %e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let log = log.new(slog::o!("peer" => peer.to_string()));
+        let config = config.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(|req| respond(&log, config.current(), &handler, req));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(log, "api"; "status" => "connection failed", "error" => %e);
+            }
+        });
+    }
+}
+
+async fn respond(
+    log: &Logger,
+    config: Arc<BotConfig>,
+    handler: &CommandHandler,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if req.uri().path() != "/lookup" {
+        return Ok(error(StatusCode::NOT_FOUND, "not found"));
+    }
+    if req.method() != Method::POST {
+        return Ok(error(StatusCode::METHOD_NOT_ALLOWED, "use POST"));
+    }
+    if !authorized(req.headers(), config.api.token.as_deref()) {
+        return Ok(error(StatusCode::UNAUTHORIZED, "bad token"));
+    }
+
+    let body = match Limited::new(req.into_body(), MAX_BODY_BYTES)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(error(StatusCode::PAYLOAD_TOO_LARGE, "request too large")),
+    };
+    let cmd = match serde_json::from_slice(&body)
+        .map_err(|e| e.to_string())
+        .and_then(|request| to_command(request, &config))
+    {
+        Ok(cmd) => cmd,
+        Err(e) => return Ok(error(StatusCode::BAD_REQUEST, &e)),
+    };
+
+    info!(log, "api"; "command" => %cmd);
+    let (res, origin) = match handler.spawn(cmd) {
+        Some((res, origin)) => (res.await, origin),
+        None => return Ok(error(StatusCode::SERVICE_UNAVAILABLE, "busy")),
+    };
+
+    Ok(match res.as_deref() {
+        Ok(Ok(info)) => reply(
+            StatusCode::OK,
+            json!({ "info": info, "cached": origin == Origin::Cache }),
+        ),
+        Ok(Err(e)) => error(StatusCode::BAD_GATEWAY, &e.to_string()),
+        Err(_) => error(StatusCode::INTERNAL_SERVER_ERROR, "lookup abandoned"),
+    })
+}
+
+fn reply(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::new(Bytes::from(body.to_string())));
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    res
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    reply(status, json!({ "error": message }))
+}
+
+/// Whether a request bears the configured bearer token, if any
+fn authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| {
+            // Compare in constant time, so the token can't be guessed byte by byte
+            given.len() == token.len()
+                && given
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+        })
+}
+
+#[test]
+fn test_authorized() {
+    let mut headers = HeaderMap::new();
+    assert!(authorized(&headers, None));
+    assert!(!authorized(&headers, Some("secret")));
+
+    headers.insert(AUTHORIZATION, "Bearer secret".parse().unwrap());
+    assert!(authorized(&headers, Some("secret")));
+    assert!(!authorized(&headers, Some("secrets")));
+    assert!(!authorized(&headers, Some("terces")));
+}
+
+/// Interpret text as a command if it has the command prefix, or otherwise a
+/// URL, as with `--once`
+pub fn parse_lookup(text: &str, config: &BotConfig) -> Result<BotCommand, String> {
+    let request = if text.starts_with(&config.command.prefix) {
+        LookupRequest::Command(text.to_string())
+    } else {
+        LookupRequest::Url(text.to_string())
+    };
+    to_command(request, config)
+}
+
+/// Turn a request into the command we'd run for it on IRC.  Only lookups are
+/// available, not commands which depend on a channel.
+fn to_command(request: LookupRequest, config: &BotConfig) -> Result<BotCommand, String> {
+    let first_url = |text: &str| {
+        extract_urls(text, &config.url, config.twitter.links())
+            .into_iter()
+            .next()
+            .map(|url| BotCommand::Url(url, config.url_options(None, "")))
+            .ok_or_else(|| "no usable URL".to_string())
+    };
+
+    let content = match request {
+        LookupRequest::Url(url) => return first_url(&url),
+        LookupRequest::Command(content) => content,
+    };
+    let (spec, args) = registry::parse(&content, &config.command.prefix, config)
+        .ok_or_else(|| "unknown command".to_string())?;
+    if spec.needs_args && args.is_empty() {
+        return Err(format!("{} needs arguments", spec.names[0]));
+    }
+
+    match spec.action {
+        Action::Title => first_url(&args),
+        Action::Omdb(_) if omdb::is_imdb_id(&args) => Ok(BotCommand::OmdbId(args)),
+        Action::Omdb(kind) => Ok(BotCommand::Omdb(kind, args)),
+        Action::OmdbSearch => Ok(BotCommand::OmdbSearch("Any", args)),
+        Action::Wolfram => Ok(BotCommand::Wolfram(args)),
+        _ => Err(format!("{} only works on IRC", spec.names[0])),
+    }
+}
+
+#[test]
+fn test_to_command() {
+    let mut config = BotConfig::default();
+    let command = |request: &str, config: &BotConfig| {
+        to_command(serde_json::from_str(request).unwrap(), config).map(|cmd| cmd.to_string())
+    };
+
+    assert_eq!(
+        command(r#"{"url": "https://example.com/"}"#, &config),
+        Ok("Url(https://example.com/)".to_string())
+    );
+    assert_eq!(
+        command(r#"{"command": ".title see https://example.com/"}"#, &config),
+        Ok("Url(https://example.com/)".to_string())
+    );
+    assert!(command(r#"{"url": "not a url"}"#, &config).is_err());
+    assert!(command(r#"{"command": ".lasturl"}"#, &config).is_err());
+    assert!(command(r#"{"command": ".imdb the matrix"}"#, &config).is_err());
+
+    config.omdb.api_key = Some("key".to_string());
+    assert_eq!(
+        command(r#"{"command": ".imdb tt0133093"}"#, &config),
+        Ok("OmdbId(tt0133093)".to_string())
+    );
+    assert_eq!(
+        command(r#"{"command": ".film the matrix"}"#, &config),
+        Ok("Omdb(Movie, the matrix)".to_string())
+    );
+    assert!(serde_json::from_str::<LookupRequest>(r#"{"other": "x"}"#).is_err());
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::Utc;
+use slog::{error, Logger};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+/// Someone running a command, for the benefit of channel operators
+#[derive(Debug, Clone)]
+pub struct AuditEntry<'a> {
+    pub network: &'a str,
+    pub channel: &'a str,
+    pub source: &'a str,
+    pub command: &'a str,
+    pub args: &'a str,
+}
+
+impl AuditEntry<'_> {
+    fn to_line(&self) -> String {
+        let mut line = serde_json::json!({
+            "time": Utc::now().to_rfc3339(),
+            "network": self.network,
+            "channel": self.channel,
+            "source": self.source,
+            "command": self.command,
+            "args": self.args,
+        })
+        .to_string();
+        line.push('\n');
+        line
+    }
+}
+
+/// Append an entry to the audit log in the background.  The file is reopened
+/// for each entry so it can be rotated freely.
+pub fn record(log: &Logger, path: &Path, entry: AuditEntry<'_>) {
+    let log = log.clone();
+    let path = path.to_path_buf();
+    let line = entry.to_line();
+
+    tokio::spawn(async move {
+        if let Err(e) = append(&path, &line).await {
+            error!(log, "audit"; "status" => "failed", "error" => %e, "path" => %path.display());
+        }
+    });
+}
+
+async fn append(path: &Path, line: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+}
+
+#[test]
+fn test_audit_line() {
+    let entry = AuditEntry {
+        network: "example",
+        channel: "#chan",
+        source: "alice",
+        command: "imdb",
+        args: "the \"matrix\"",
+    };
+    let line = entry.to_line();
+    assert!(line.ends_with('\n'));
+
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(value["channel"], "#chan");
+    assert_eq!(value["command"], "imdb");
+    assert_eq!(value["args"], "the \"matrix\"");
+}
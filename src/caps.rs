@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+
+use irc::proto::CapSubCommand;
+
+/// The server repeats our own messages back to us once delivered
+pub const ECHO_MESSAGE: &str = "echo-message";
+
+/// IRCv3 capabilities we want, those the server offers, and those enabled
+#[derive(Debug, Default)]
+pub struct Capabilities {
+    wanted: Vec<String>,
+    available: HashSet<String>,
+    enabled: HashSet<String>,
+}
+
+impl Capabilities {
+    pub fn new(wanted: &[String]) -> Self {
+        Self {
+            wanted: wanted.to_vec(),
+            ..Self::default()
+        }
+    }
+
+    pub fn wants_any(&self) -> bool {
+        !self.wanted.is_empty()
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    /// Whether the server offers a capability, whether or not we've enabled it
+    pub fn is_available(&self, cap: &str) -> bool {
+        self.available.contains(cap)
+    }
+
+    /// Capabilities we want which the server doesn't offer
+    pub fn unavailable(&self) -> Vec<&str> {
+        self.wanted
+            .iter()
+            .map(String::as_str)
+            .filter(|cap| !self.is_available(cap))
+            .collect()
+    }
+
+    /// Capabilities the server offers, space-separated and sorted
+    pub fn available(&self) -> String {
+        sorted(&self.available)
+    }
+
+    /// Currently enabled capabilities, space-separated and sorted
+    pub fn enabled(&self) -> String {
+        sorted(&self.enabled)
+    }
+
+    /// Update state from a server CAP message, returning any capabilities we
+    /// should now request.
+    pub fn handle(&mut self, subcommand: &CapSubCommand, list: &str) -> Vec<String> {
+        // Capabilities may carry values, e.g. sasl=PLAIN,EXTERNAL
+        let caps = list
+            .split_ascii_whitespace()
+            .map(|cap| cap.split('=').next().unwrap_or(cap));
+
+        match subcommand {
+            CapSubCommand::LS | CapSubCommand::NEW => {
+                let offered: Vec<_> = caps.map(str::to_string).collect();
+                self.available.extend(offered.iter().cloned());
+                offered
+                    .into_iter()
+                    .filter(|cap| self.wanted.contains(cap) && !self.enabled.contains(cap))
+                    .collect()
+            }
+            CapSubCommand::ACK => {
+                for cap in caps {
+                    if let Some(cap) = cap.strip_prefix('-') {
+                        self.enabled.remove(cap);
+                    } else {
+                        self.enabled.insert(cap.to_string());
+                    }
+                }
+                vec![]
+            }
+            CapSubCommand::DEL => {
+                for cap in caps {
+                    self.available.remove(cap);
+                    self.enabled.remove(cap);
+                }
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+fn sorted(caps: &HashSet<String>) -> String {
+    let mut caps: Vec<_> = caps.iter().map(String::as_str).collect();
+    caps.sort_unstable();
+    caps.join(" ")
+}
+
+/// The capability list of a CAP message, which is the trailing parameter
+/// after any multi-line `*` marker.
+pub fn cap_list<'a>(param: &'a Option<String>, trailing: &'a Option<String>) -> &'a str {
+    trailing.as_deref().or(param.as_deref()).unwrap_or_default()
+}
+
+#[test]
+fn test_capabilities() {
+    let mut caps = Capabilities::new(&["message-tags".to_string(), "echo-message".to_string()]);
+    assert!(caps.wants_any());
+
+    assert_eq!(
+        caps.handle(&CapSubCommand::LS, "sasl=PLAIN multi-prefix echo-message"),
+        vec!["echo-message"]
+    );
+    assert!(!caps.is_enabled(ECHO_MESSAGE));
+    caps.handle(&CapSubCommand::ACK, "echo-message");
+    assert_eq!(caps.enabled(), "echo-message");
+    assert!(caps.is_enabled(ECHO_MESSAGE));
+
+    assert_eq!(
+        caps.handle(&CapSubCommand::NEW, "message-tags echo-message"),
+        vec!["message-tags"]
+    );
+    caps.handle(&CapSubCommand::ACK, "message-tags");
+    assert_eq!(caps.enabled(), "echo-message message-tags");
+
+    caps.handle(&CapSubCommand::DEL, "message-tags");
+    assert_eq!(caps.enabled(), "echo-message");
+
+    caps.handle(&CapSubCommand::DEL, "echo-message");
+    assert!(!caps.is_enabled(ECHO_MESSAGE));
+}
+
+#[tokio::test]
+async fn test_capabilities_from_server() {
+    use irc::client::prelude::*;
+
+    // A multi-line CAP LS reply, as a 302-aware server sends a long list
+    let mut client = Client::from_config(Config {
+        nickname: Some("annoirc".to_string()),
+        use_mock_connection: true,
+        mock_initial_value: Some(
+            ":irc.example CAP * LS * :sasl=PLAIN,EXTERNAL server-time\r\n\
+             :irc.example CAP * LS :message-tags echo-message\r\n\
+             :irc.example CAP * ACK :echo-message\r\n"
+                .to_string(),
+        ),
+        ..Config::default()
+    })
+    .await
+    .unwrap();
+
+    let mut caps = Capabilities::new(&["echo-message".to_string(), "batch".to_string()]);
+    let mut requested = vec![];
+    for message in client.stream().unwrap().collect().await.unwrap() {
+        if let Command::CAP(_, subcommand, param, trailing) = &message.command {
+            requested.extend(caps.handle(subcommand, cap_list(param, trailing)));
+        }
+    }
+
+    assert_eq!(
+        caps.available(),
+        "echo-message message-tags sasl server-time"
+    );
+    assert!(caps.is_available("sasl"));
+    assert!(!caps.is_available("batch"));
+    assert_eq!(caps.unavailable(), vec!["batch"]);
+    assert_eq!(requested, vec!["echo-message"]);
+    assert!(caps.is_enabled(ECHO_MESSAGE));
+    assert!(!caps.is_enabled("message-tags"));
+}
@@ -1,27 +1,46 @@
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     fmt,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use encoding_rs::{Encoding, UTF_8};
 use futures::{
     channel::{mpsc, oneshot},
     future::Shared,
     stream::StreamExt,
     FutureExt,
 };
+use lazy_static::lazy_static;
 use lru_time_cache::LruCache;
-use reqwest::header::{HeaderMap, ACCEPT_LANGUAGE, USER_AGENT};
+use regex::Regex;
+use reqwest::{
+    cookie::Jar,
+    header::{HeaderMap, ACCEPT_LANGUAGE, RETRY_AFTER, USER_AGENT},
+    StatusCode,
+};
 use scraper::{Html, Selector};
-use serde::Deserialize;
-use slog::{info, o, Logger};
-use tokio::time::timeout;
+use serde::{Deserialize, Serialize};
+use slog::{debug, info, o, Logger};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{timeout, Instant},
+};
 use url::Url;
 
-use crate::{config::*, irc_string::*, omdb, wolfram::*, youtube::*};
+use crate::{
+    config::*, factoid::Factoids, gemini::gemini_lookup, irc_string::*, omdb, tmdb, wolfram::*,
+    youtube::*,
+};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct UrlInfo {
     pub url: Url,
     pub title: IrcString,
@@ -30,48 +49,1141 @@ pub struct UrlInfo {
 
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum BotCommand {
-    Url(Url),
+    Url(Url, UrlOptions),
     Omdb(&'static str, String),
+    OmdbId(String),
+    OmdbSearch(&'static str, String),
     Wolfram(String),
+    /// A video ID and language, from a YouTube URL
+    YouTube(String, Option<String>),
 }
 
 // Consider Boxing these, or moving the Arc internally
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", content = "info", rename_all = "lowercase")]
 pub enum Info {
     Url(UrlInfo),
     Movie(omdb::Movie),
+    Candidates(Vec<omdb::Candidate>),
     YouTube(YouTube),
     Wolfram(Vec<WolframPod>),
 }
 
+impl Info {
+    /// A rough estimate of the memory used by a result, mostly its text
+    pub fn estimated_size(&self) -> usize {
+        let text: usize = match self {
+            Info::Url(info) => {
+                info.url.as_str().len()
+                    + info.title.len()
+                    + info.desc.as_ref().map_or(0, |d| d.len())
+            }
+            Info::Movie(movie) => {
+                [
+                    &movie.title,
+                    &movie.plot,
+                    &movie.rated,
+                    &movie.released,
+                    &movie.runtime,
+                    &movie.genre,
+                    &movie.director,
+                    &movie.imdb_rating,
+                    &movie.imdb_votes,
+                    &movie.imdb_id,
+                    &movie.metascore,
+                ]
+                .iter()
+                .map(|s| s.len())
+                .sum::<usize>()
+                    + movie
+                        .alternatives
+                        .iter()
+                        .map(|c| {
+                            std::mem::size_of_val(c)
+                                + c.title.len()
+                                + c.year.len()
+                                + c.imdb_id.len()
+                        })
+                        .sum::<usize>()
+            }
+            Info::Candidates(candidates) => candidates
+                .iter()
+                .map(|c| std::mem::size_of_val(c) + c.title.len() + c.year.len() + c.imdb_id.len())
+                .sum(),
+            Info::YouTube(item) => {
+                item.id.len() + item.title.len() + item.description.len() + item.channel.len()
+            }
+            Info::Wolfram(pods) => pods
+                .iter()
+                .map(|pod| {
+                    std::mem::size_of_val(pod)
+                        + pod.title.len()
+                        + pod.values.iter().map(|v| v.len()).sum::<usize>()
+                })
+                .sum(),
+        };
+
+        std::mem::size_of::<Info>() + text
+    }
+}
+
+#[test]
+fn test_info_json() {
+    use serde_json::json;
+
+    let candidate = omdb::Candidate {
+        title: "The Matrix".into(),
+        year: "1999".into(),
+        imdb_id: "tt0133093".into(),
+        kind: omdb::Kind::Movie,
+    };
+    let movie = omdb::Movie {
+        title: "The Matrix".into(),
+        kind: omdb::Kind::Movie,
+        year: "1999".into(),
+        plot: "A hacker learns the truth.".into(),
+        rated: "R".into(),
+        released: "31 Mar 1999".into(),
+        runtime: "136 min".into(),
+        genre: "Action, Sci-Fi".into(),
+        director: "Lana Wachowski, Lilly Wachowski".into(),
+        imdb_rating: "8.7".into(),
+        imdb_votes: "2,000,000".into(),
+        imdb_id: "tt0133093".into(),
+        metascore: "73".into(),
+        alternatives: vec![],
+    };
+    let youtube = YouTube {
+        id: "dQw4w9WgXcQ".into(),
+        title: "Never Gonna Give You Up".into(),
+        description: "The official video".into(),
+        published_at: DateTime::parse_from_rfc3339("2009-10-25T06:57:33+00:00").ok(),
+        duration: Duration::from_secs(213),
+        channel: "Rick Astley".into(),
+        views: 1_000,
+        likes: 10,
+    };
+
+    let cases = vec![
+        (
+            Info::Url(UrlInfo {
+                url: Url::parse("https://example.com/a?b").unwrap(),
+                title: "Example".into(),
+                desc: None,
+            }),
+            json!({"type": "url", "info": {
+                "url": "https://example.com/a?b", "title": "Example", "desc": null
+            }}),
+        ),
+        (
+            Info::Movie(movie),
+            json!({"type": "movie", "info": {
+                "title": "The Matrix", "kind": "Movie", "year": "1999",
+                "plot": "A hacker learns the truth.", "rated": "R",
+                "released": "31 Mar 1999", "runtime": "136 min",
+                "genre": "Action, Sci-Fi", "director": "Lana Wachowski, Lilly Wachowski",
+                "imdb_rating": "8.7", "imdb_votes": "2,000,000", "imdb_id": "tt0133093",
+                "metascore": "73", "alternatives": []
+            }}),
+        ),
+        (
+            Info::Candidates(vec![candidate]),
+            json!({"type": "candidates", "info": [{
+                "title": "The Matrix", "year": "1999", "imdb_id": "tt0133093", "kind": "Movie"
+            }]}),
+        ),
+        (
+            Info::YouTube(youtube),
+            json!({"type": "youtube", "info": {
+                "id": "dQw4w9WgXcQ", "title": "Never Gonna Give You Up",
+                "description": "The official video",
+                "published_at": "2009-10-25T06:57:33Z", "duration": 213,
+                "channel": "Rick Astley", "views": 1000, "likes": 10
+            }}),
+        ),
+        (
+            Info::Wolfram(vec![WolframPod {
+                title: "Result".into(),
+                values: vec!["2".into()],
+            }]),
+            json!({"type": "wolfram", "info": [{"title": "Result", "values": ["2"]}]}),
+        ),
+    ];
+
+    for (info, expected) in cases {
+        assert_eq!(serde_json::to_value(&info).unwrap(), expected);
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct Wiki {
     title: String,
+    /// standard, disambiguation, mainpage, or no-extract
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
     extract: String,
+    description: Option<String>,
+    coordinates: Option<WikiCoordinates>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WikiCoordinates {
+    lat: f64,
+    lon: f64,
+}
+
+impl Wiki {
+    /// The extract, with any extras the configuration asks for
+    fn summary(&self, config: &WikipediaConfig) -> Option<String> {
+        // The extract of these is just "Foo may refer to:"
+        if self.kind == "disambiguation" {
+            return Some("Disambiguation page".to_string());
+        }
+
+        let extract = first_sentences(&self.extract, config.max_sentences as usize);
+        let mut summary = match &self.description {
+            Some(desc) if config.include_description && !desc.is_empty() => {
+                if extract.is_empty() {
+                    desc.clone()
+                } else {
+                    format!("{} - {}", desc, extract)
+                }
+            }
+            _ if extract.is_empty() => return None,
+            _ => extract.to_string(),
+        };
+
+        if let (true, Some(coords)) = (config.include_coordinates, &self.coordinates) {
+            summary.push_str(&format!(
+                " ({:.4}°{}, {:.4}°{})",
+                coords.lat.abs(),
+                if coords.lat < 0.0 { 'S' } else { 'N' },
+                coords.lon.abs(),
+                if coords.lon < 0.0 { 'W' } else { 'E' }
+            ));
+        }
+
+        Some(summary)
+    }
+}
+
+/// The first few sentences of some prose, or all of it if `max` is 0.  A
+/// sentence ends with punctuation followed by a capitalised word, so initials
+/// like "J. R. R. Tolkien" don't count, though some abbreviations will.
+fn first_sentences(text: &str, max: usize) -> &str {
+    if max == 0 {
+        return text;
+    }
+
+    let mut count = 0;
+    let mut word_len = 0;
+    for (i, c) in text.char_indices() {
+        if matches!(c, '.' | '!' | '?') && word_len > 1 {
+            let rest = text[i + 1..].trim_start();
+            let ends = rest.len() < text[i + 1..].len()
+                && rest.chars().next().is_some_and(char::is_uppercase);
+            if ends {
+                count += 1;
+                if count == max {
+                    return &text[..=i];
+                }
+            }
+        }
+
+        word_len = if c.is_whitespace() { 0 } else { word_len + 1 };
+    }
+
+    text
+}
+
+#[test]
+fn test_first_sentences() {
+    let text =
+        "J. R. R. Tolkien was an English writer. He wrote The Hobbit! Was he a philologist? Yes.";
+    assert_eq!(first_sentences(text, 0), text);
+    assert_eq!(
+        first_sentences(text, 1),
+        "J. R. R. Tolkien was an English writer."
+    );
+    assert_eq!(
+        first_sentences(text, 2),
+        "J. R. R. Tolkien was an English writer. He wrote The Hobbit!"
+    );
+    assert_eq!(first_sentences(text, 10), text);
+    assert_eq!(
+        first_sentences("Version 1.5 is out", 1),
+        "Version 1.5 is out"
+    );
+}
+
+/// The REST summary endpoint for an article, given the path following /wiki/.
+/// Titles may arrive with spaces as underscores or percent-encoded, and can
+/// contain slashes, which must be escaped as a single path segment.
+fn wikipedia_summary_url(lang: &str, article: &str) -> Result<Url> {
+    let title = percent_encoding::percent_decode_str(article)
+        .decode_utf8()?
+        .replace(' ', "_");
+
+    let mut url = Url::parse(&format!(
+        "https://{}.wikipedia.org/api/rest_v1/page/summary",
+        lang
+    ))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid Wikipedia URL"))?
+        .push(&title);
+    Ok(url)
+}
+
+#[test]
+fn test_wikipedia_summary_url() {
+    let url = |article| wikipedia_summary_url("en", article).unwrap().to_string();
+    let base = "https://en.wikipedia.org/api/rest_v1/page/summary/";
+
+    assert_eq!(url("New_York_City"), format!("{}New_York_City", base));
+    assert_eq!(url("New%20York%20City"), format!("{}New_York_City", base));
+    assert_eq!(url("AC/DC"), format!("{}AC%2FDC", base));
+    assert_eq!(url("AC%2FDC"), format!("{}AC%2FDC", base));
+    assert_eq!(
+        url("Caf%C3%A9_(disambiguation)"),
+        format!("{}Caf%C3%A9_(disambiguation)", base)
+    );
+}
+
+#[test]
+fn test_wiki_disambiguation() {
+    let wiki: Wiki = serde_json::from_str(
+        r#"{
+            "type": "disambiguation",
+            "title": "Mercury",
+            "extract": "Mercury may refer to:",
+            "description": "Topics referred to by the same term"
+        }"#,
+    )
+    .unwrap();
+    let config = WikipediaConfig::default();
+    assert_eq!(wiki.summary(&config).unwrap(), "Disambiguation page");
+
+    let wiki: Wiki = serde_json::from_str(r#"{ "type": "no-extract", "title": "Empty" }"#).unwrap();
+    assert_eq!(wiki.summary(&config), None);
+}
+
+#[test]
+fn test_wiki_summary() {
+    let wiki: Wiki = serde_json::from_str(
+        r#"{
+            "title": "London",
+            "description": "Capital city of the United Kingdom",
+            "extract": "London is the capital of England.",
+            "coordinates": { "lat": 51.50722222, "lon": -0.1275 }
+        }"#,
+    )
+    .unwrap();
+
+    let mut config = WikipediaConfig::default();
+    assert_eq!(
+        wiki.summary(&config).unwrap(),
+        "London is the capital of England."
+    );
+    assert_eq!(config.max_sentences, 2);
+
+    config.include_description = true;
+    config.include_coordinates = true;
+    assert_eq!(
+        wiki.summary(&config).unwrap(),
+        "Capital city of the United Kingdom - London is the capital of England. (51.5072°N, 0.1275°W)"
+    );
+}
+
+/// Supplementary page metadata, extracted to help tune preview quality
+#[derive(Debug, Default, PartialEq)]
+struct PageMetadata {
+    icon: Option<String>,
+    theme_color: Option<String>,
+    site_name: Option<String>,
+}
+
+impl PageMetadata {
+    fn extract(fragment: &Html) -> Self {
+        lazy_static! {
+            static ref ICON: Selector =
+                Selector::parse(r#"link[rel~="icon"], link[rel="apple-touch-icon"]"#).unwrap();
+            static ref THEME_COLOR: Selector =
+                Selector::parse(r#"meta[name="theme-color"]"#).unwrap();
+            static ref SITE_NAME: Selector =
+                Selector::parse(r#"meta[property="og:site_name"], meta[name="application-name"]"#)
+                    .unwrap();
+        }
+
+        let attr = |selector: &Selector, attr: &str| {
+            fragment
+                .select(selector)
+                .find_map(|n| n.value().attr(attr))
+                .map(|s| sanitize(s, 200))
+                .filter(|s| !s.is_empty())
+        };
+
+        Self {
+            icon: attr(&ICON, "href"),
+            theme_color: attr(&THEME_COLOR, "content"),
+            site_name: attr(&SITE_NAME, "content"),
+        }
+    }
+}
+
+#[test]
+fn test_page_metadata() {
+    let html = Html::parse_document(
+        r##"<html><head><title>Test</title>
+        <link rel="shortcut icon" href="/favicon.ico">
+        <meta name="theme-color" content="#ff0000">
+        <meta property="og:site_name" content="Example">
+        </head></html>"##,
+    );
+    assert_eq!(
+        PageMetadata::extract(&html),
+        PageMetadata {
+            icon: Some("/favicon.ico".to_string()),
+            theme_color: Some("#ff0000".to_string()),
+            site_name: Some("Example".to_string()),
+        }
+    );
+    assert_eq!(
+        PageMetadata::extract(&Html::parse_document("<title>Test</title>")),
+        PageMetadata::default()
+    );
+}
+
+/// A title without a trailing separator and site name, if it has one
+fn strip_site_name<'a>(title: &'a str, site_name: &str) -> Option<&'a str> {
+    [" - ", " | ", " – ", " — "].iter().find_map(|sep| {
+        let (rest, suffix) = title.rsplit_once(sep)?;
+        Some(rest.trim_end())
+            .filter(|rest| !rest.is_empty() && suffix.trim().eq_ignore_ascii_case(site_name))
+    })
+}
+
+#[test]
+fn test_strip_site_name() {
+    assert_eq!(
+        strip_site_name("Rust 2.0 announced - Example News", "Example News"),
+        Some("Rust 2.0 announced")
+    );
+    assert_eq!(
+        strip_site_name("Rust 2.0 announced | example news", "Example News"),
+        Some("Rust 2.0 announced")
+    );
+    assert_eq!(
+        strip_site_name("Rust 2.0 announced - Other News", "Example News"),
+        None
+    );
+    assert_eq!(
+        strip_site_name("Review: Example News - the good parts", "Example News"),
+        None
+    );
+    assert_eq!(strip_site_name("Example News", "Example News"), None);
+    assert_eq!(strip_site_name(" - Example News", "Example News"), None);
 }
 
 type Response = Shared<oneshot::Receiver<Arc<Result<Info>>>>;
+
+/// Where a command's response is coming from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// A previous identical command, which may still be running
+    Cache,
+    /// A new lookup
+    Fresh,
+}
+
+/// A cached response, which may expire early if it turned out to be an error.
+///
+/// Expiry uses tokio's clock rather than the `LruCache`'s own, so tests can
+/// pause and advance time deterministically.
+#[derive(Clone)]
+struct CacheEntry {
+    response: Response,
+    expires: Arc<Mutex<Instant>>,
+    /// Estimated memory use once complete, zero while pending
+    size: Arc<AtomicUsize>,
+}
+
+impl CacheEntry {
+    fn new(response: Response, ttl: Duration) -> Self {
+        Self {
+            response,
+            expires: Arc::new(Mutex::new(Instant::now() + ttl)),
+            size: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Expire within `ttl` if not due to already
+    fn shorten(&self, ttl: Duration) {
+        let mut expires = self.expires.lock().unwrap();
+        *expires = (*expires).min(Instant::now() + ttl);
+    }
+
+    fn expired(&self) -> bool {
+        *self.expires.lock().unwrap() <= Instant::now()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_cache_entry_expiry() {
+    let entry = CacheEntry::new(oneshot::channel().1.shared(), Duration::from_secs(60));
+    tokio::time::advance(Duration::from_secs(59)).await;
+    assert!(!entry.expired());
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(entry.expired());
+
+    // Errors expire sooner, but never extend an entry's life
+    let entry = CacheEntry::new(oneshot::channel().1.shared(), Duration::from_secs(60));
+    entry.shorten(Duration::from_secs(10));
+    tokio::time::advance(Duration::from_secs(9)).await;
+    assert!(!entry.expired());
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(entry.expired());
+
+    let entry = CacheEntry::new(oneshot::channel().1.shared(), Duration::from_secs(5));
+    entry.shorten(Duration::from_secs(10));
+    tokio::time::advance(Duration::from_secs(5)).await;
+    assert!(entry.expired());
+}
+
+/// A service asked us to back off, optionally saying for how long
+#[derive(Debug)]
+pub struct RateLimited(pub Option<Duration>);
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(delay) => write!(f, "Rate limited, try again in {}s", delay.as_secs()),
+            None => write!(f, "Rate limited"),
+        }
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// A service responded with an unsuccessful HTTP status
+#[derive(Debug)]
+pub struct HttpStatus(pub StatusCode);
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Status {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+/// Turn an unsuccessful HTTP status into an error, noting any Retry-After
+pub fn check_status(status: StatusCode, headers: &HeaderMap) -> Result<()> {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        Err(RateLimited(retry_after(headers)).into())
+    } else if !status.is_success() {
+        Err(HttpStatus(status).into())
+    } else {
+        Ok(())
+    }
+}
+
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+#[test]
+fn test_check_status_retry_after() {
+    let mut headers = HeaderMap::new();
+    assert!(check_status(StatusCode::OK, &headers).is_ok());
+
+    let err = check_status(StatusCode::NOT_FOUND, &headers).unwrap_err();
+    assert!(err.downcast_ref::<RateLimited>().is_none());
+
+    headers.insert(RETRY_AFTER, "120".parse().unwrap());
+    let err = check_status(StatusCode::TOO_MANY_REQUESTS, &headers).unwrap_err();
+    let limited = err.downcast_ref::<RateLimited>().unwrap();
+    assert_eq!(limited.0, Some(Duration::from_secs(120)));
+    assert_eq!(limited.to_string(), "Rate limited, try again in 120s");
+
+    headers.insert(
+        RETRY_AFTER,
+        "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+    );
+    let err = check_status(StatusCode::TOO_MANY_REQUESTS, &headers).unwrap_err();
+    assert_eq!(err.downcast_ref::<RateLimited>().unwrap().0, None);
+}
+
+/// The first non-empty value matching any of the selectors, in order of
+/// preference: an element's `content` attribute if it has one, else its text.
+fn select_first(fragment: &Html, selectors: &[Selector]) -> Option<IrcString> {
+    selectors.iter().find_map(|selector| {
+        fragment
+            .select(selector)
+            .map(|n| match n.value().attr("content") {
+                Some(content) => html_escape::decode_html_entities(content).into_owned(),
+                None => n.text().collect::<String>(),
+            })
+            .map(IrcString::from)
+            .find(|s| !s.is_empty())
+    })
+}
+
+#[test]
+fn test_select_first() {
+    let doc = Html::parse_document(
+        r#"<head><title>Page</title><meta property="og:title" content="Better &amp; shorter"><meta name="description" content=""></head>"#,
+    );
+    let selectors =
+        |s: &[&str]| -> Vec<Selector> { s.iter().map(|s| Selector::parse(s).unwrap()).collect() };
+    assert_eq!(
+        select_first(&doc, &selectors(&["title"])),
+        Some("Page".into())
+    );
+    assert_eq!(
+        select_first(&doc, &selectors(&[r#"meta[property="og:title"]"#, "title"])),
+        Some("Better & shorter".into())
+    );
+    assert_eq!(
+        select_first(&doc, &selectors(&[r#"meta[name="description"]"#])),
+        None
+    );
+}
+
+/// Decode a page in the charset its Content-Type declares, or failing that
+/// one declared by a `<meta>` tag near the start, or else as UTF-8.  A byte
+/// order mark overrides both, as it does in browsers.
+fn decode_body<'a>(body: &'a [u8], content_type: Option<&str>) -> Cow<'a, str> {
+    lazy_static! {
+        static ref META_CHARSET: regex::bytes::Regex =
+            regex::bytes::Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?([\w.:-]+)"#).unwrap();
+    }
+
+    let declared = content_type
+        .and_then(|ct| ct.parse::<mime::Mime>().ok())
+        .and_then(|mime| {
+            mime.get_param(mime::CHARSET)
+                .and_then(|charset| Encoding::for_label(charset.as_str().as_bytes()))
+        });
+    // Browsers only look this far for a <meta> declaration
+    let sniffed = || {
+        META_CHARSET
+            .captures(&body[..body.len().min(1024)])
+            .and_then(|caps| Encoding::for_label(&caps[1]))
+    };
+
+    declared.or_else(sniffed).unwrap_or(UTF_8).decode(body).0
+}
+
+#[test]
+fn test_decode_body() {
+    let title = |body: &[u8], content_type| {
+        let selectors = [Selector::parse("title").unwrap()];
+        select_first(
+            &Html::parse_document(&decode_body(body, content_type)),
+            &selectors,
+        )
+    };
+
+    // 日本語 in Shift_JIS
+    let sjis = b"<title>\x93\xfa\x96\x7b\x8c\xea</title>";
+    assert_eq!(
+        title(sjis, Some("text/html; charset=Shift_JIS")),
+        Some("日本語".into())
+    );
+    // Привет in Windows-1251, declared only in the page
+    let cp1251 = b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1251\"><title>\xcf\xf0\xe8\xe2\xe5\xf2</title>";
+    assert_eq!(title(cp1251, Some("text/html")), Some("Привет".into()));
+    // The header wins over the page
+    let latin1 = b"<meta charset=\"utf-8\"><title>Caf\xe9</title>";
+    assert_eq!(
+        title(latin1, Some("text/html; charset=\"ISO-8859-1\"")),
+        Some("Café".into())
+    );
+    assert_eq!(title(latin1, None), Some("Caf\u{fffd}".into()));
+
+    // Anything unrecognised is UTF-8
+    let utf8 = "<title>Café</title>".as_bytes();
+    assert_eq!(title(utf8, None), Some("Café".into()));
+    assert_eq!(
+        title(utf8, Some("text/html; charset=klingon")),
+        Some("Café".into())
+    );
+}
+
+/// The document up to and including `</head>`, if there is one.  Titles and
+/// descriptions live here, so there's no need to parse the rest.
+fn head_region(document: &str) -> &str {
+    lazy_static! {
+        static ref HEAD_END: Regex = Regex::new(r"(?i)</head\s*>").unwrap();
+    }
+
+    HEAD_END
+        .find(document)
+        .map(|m| &document[..m.end()])
+        .unwrap_or(document)
+}
+
+#[test]
+fn test_head_region() {
+    let doc = "<html><head><title>Hi</title></HEAD ><body><p>Lots</p></body></html>";
+    assert_eq!(head_region(doc), "<html><head><title>Hi</title></HEAD >");
+    let doc = "<title>Hi</title><p>No head</p>";
+    assert_eq!(head_region(doc), doc);
+}
+
+/// Accumulates a response body within the configured limits, optionally
+/// stopping as soon as the end of the document head arrives.
+struct BodyReader {
+    buf: Vec<u8>,
+    byte_limit: usize,
+    chunks_left: u16,
+    head_only: bool,
+}
+
+impl BodyReader {
+    fn new(config: &UrlConfig) -> Self {
+        let byte_limit = config.max_kb as usize * 1024;
+        Self {
+            buf: Vec::with_capacity(byte_limit * 2),
+            byte_limit,
+            chunks_left: config.max_chunks,
+            head_only: config.parse_head_only,
+        }
+    }
+
+    /// Add a chunk, returning true once there's no need to read any more
+    fn push(&mut self, chunk: &[u8]) -> bool {
+        lazy_static! {
+            static ref HEAD_END: regex::bytes::Regex =
+                regex::bytes::Regex::new(r"(?i)</head\s*>").unwrap();
+        }
+
+        // Allow for the closing tag straddling chunks
+        let search_from = self.buf.len().saturating_sub(16);
+        self.buf.extend_from_slice(chunk);
+        self.chunks_left = self.chunks_left.saturating_sub(1);
+
+        self.buf.len() >= self.byte_limit
+            || self.chunks_left == 0
+            || (self.head_only && HEAD_END.is_match(&self.buf[search_from..]))
+    }
+}
+
+#[test]
+fn test_body_reader_stops_after_head() {
+    let chunks = [
+        "<html><head><title>Hi</title></he",
+        "ad>",
+        "<body>Lots</body>",
+    ];
+
+    let mut body = BodyReader::new(&UrlConfig::default());
+    let read = chunks
+        .iter()
+        .take_while(|c| !body.push(c.as_bytes()))
+        .count();
+    assert_eq!(read, 1);
+    assert_eq!(body.buf, b"<html><head><title>Hi</title></head>");
+
+    let config = UrlConfig {
+        parse_head_only: false,
+        max_chunks: 2,
+        ..UrlConfig::default()
+    };
+    let mut body = BodyReader::new(&config);
+    assert!(!body.push(chunks[0].as_bytes()));
+    // The head is complete, but without parse_head_only only the chunk limit stops us
+    assert!(body.push(chunks[1].as_bytes()));
+}
+
+/// Evict the least recently used completed entries until the estimated size
+/// of the cache fits within a budget.
+fn evict_to_budget(cache: &mut LruCache<BotCommand, CacheEntry>, max_bytes: usize) {
+    // Most recently used first
+    let entries: Vec<_> = cache
+        .peek_iter()
+        .map(|(command, entry)| (command.clone(), entry.size()))
+        .collect();
+    let mut total: usize = entries.iter().map(|(_, size)| size).sum();
+
+    for (command, size) in entries.into_iter().rev() {
+        if total <= max_bytes {
+            break;
+        }
+        if size > 0 {
+            cache.remove(&command);
+            total -= size;
+        }
+    }
+}
+
+#[test]
+fn test_evict_to_budget() {
+    let mut cache = LruCache::with_capacity(10);
+    for (i, size) in [100, 0, 300, 200].iter().enumerate() {
+        cache.insert(
+            BotCommand::Wolfram(i.to_string()),
+            CacheEntry {
+                size: Arc::new(AtomicUsize::new(*size)),
+                ..CacheEntry::new(oneshot::channel().1.shared(), Duration::from_secs(60))
+            },
+        );
+    }
+
+    evict_to_budget(&mut cache, 600);
+    assert_eq!(cache.len(), 4);
+
+    // The oldest completed entries go first, leaving the pending one
+    evict_to_budget(&mut cache, 300);
+    let mut left: Vec<_> = cache.peek_iter().map(|(c, _)| c.to_string()).collect();
+    left.sort();
+    assert_eq!(left, vec!["Wolfram(1)", "Wolfram(3)"]);
+}
+
+/// A host's concurrency limit, and the semaphore enforcing it
+type HostSemaphore = (u8, Arc<Semaphore>);
+
+/// Limits concurrent lookups to any one host
+#[derive(Debug, Clone, Default)]
+struct HostLimiter(Arc<Mutex<HashMap<String, HostSemaphore>>>);
+
+impl HostLimiter {
+    /// Wait until fewer than `limit` lookups to a host are in progress
+    async fn acquire(&self, host: &str, limit: u8) -> OwnedSemaphorePermit {
+        let semaphore = {
+            let mut hosts = self.0.lock().unwrap();
+            // Forget idle hosts, which nobody else holds a reference to
+            hosts.retain(|_, (_, semaphore)| Arc::strong_count(semaphore) > 1);
+
+            let entry = hosts
+                .entry(host.to_ascii_lowercase())
+                .or_insert_with(|| (limit, Arc::new(Semaphore::new(limit as usize))));
+            if entry.0 != limit {
+                *entry = (limit, Arc::new(Semaphore::new(limit as usize)));
+            }
+            entry.1.clone()
+        };
+
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("host semaphore is never closed")
+    }
+}
+
+#[tokio::test]
+async fn test_host_limiter() {
+    let hosts = HostLimiter::default();
+    let wait = Duration::from_millis(50);
+
+    let first = hosts.acquire("example.com", 1).await;
+    assert!(timeout(wait, hosts.acquire("EXAMPLE.com", 1))
+        .await
+        .is_err());
+    assert!(timeout(wait, hosts.acquire("example.org", 1)).await.is_ok());
+
+    let queued = tokio::spawn({
+        let hosts = hosts.clone();
+        async move { hosts.acquire("example.com", 1).await }
+    });
+    tokio::time::sleep(wait).await;
+    assert!(!queued.is_finished());
+
+    drop(first);
+    assert!(timeout(wait, queued).await.is_ok());
+}
+
+/// How long an error result should remain cached
+fn error_ttl(error: &anyhow::Error, config: &CommandConfig) -> Duration {
+    let ttl = Duration::from_secs(
+        config
+            .error_cache_time_secs
+            .unwrap_or(config.cache_time_secs) as u64,
+    );
+
+    match error.downcast_ref::<RateLimited>() {
+        Some(RateLimited(Some(delay))) => ttl.min(*delay),
+        _ => ttl,
+    }
+}
 type Work = std::pin::Pin<Box<dyn futures::Future<Output = Result<(), Arc<Result<Info>>>> + Send>>;
 
+/// Bot-wide counters, reported by `.stats`
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    commands: AtomicU64,
+    cache_hits: AtomicU64,
+    previews: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            commands: AtomicU64::default(),
+            cache_hits: AtomicU64::default(),
+            previews: AtomicU64::default(),
+        }
+    }
+}
+
+impl Stats {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Commands run, including those answered from cache
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// URL previews sent to channels
+    pub fn previews(&self) -> u64 {
+        self.previews.load(Ordering::Relaxed)
+    }
+
+    pub fn record_preview(&self) {
+        self.previews.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 #[derive(Clone)]
 pub struct CommandHandler {
     log: Logger,
     config: ConfigMonitor,
-    client: reqwest::Client,
+    client: Arc<Mutex<reqwest::Client>>,
+    hosts: HostLimiter,
     queue: mpsc::Sender<Work>,
-    cache: Arc<Mutex<LruCache<BotCommand, Response>>>,
+    cache: Arc<Mutex<LruCache<BotCommand, CacheEntry>>>,
+    pub stats: Arc<Stats>,
+    pub factoids: Arc<Factoids>,
 }
 
 impl fmt::Display for BotCommand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Url(url) => write!(f, "Url({})", url),
+            Self::Url(url, options) => {
+                write!(f, "Url({}", url)?;
+                if options.policy == UrlPolicy::Anywhere {
+                    write!(f, ", anywhere")?;
+                }
+                if let Some(language) = &options.language {
+                    write!(f, ", {}", language)?;
+                }
+                write!(f, ")")
+            }
             Self::Omdb(kind, search) => write!(f, "Omdb({}, {})", kind, search),
+            Self::OmdbId(id) => write!(f, "OmdbId({})", id),
+            Self::OmdbSearch(kind, search) => write!(f, "OmdbSearch({}, {})", kind, search),
             Self::Wolfram(query) => write!(f, "Wolfram({})", query),
+            Self::YouTube(id, None) => write!(f, "YouTube({})", id),
+            Self::YouTube(id, Some(language)) => write!(f, "YouTube({}, {})", id, language),
         }
     }
 }
 
+impl BotCommand {
+    /// A short name for the kind of command, used for per-kind configuration
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Url(..) | Self::YouTube(..) => "url",
+            Self::Omdb(_, _) | Self::OmdbId(_) | Self::OmdbSearch(_, _) => "omdb",
+            Self::Wolfram(_) => "wolfram",
+        }
+    }
+
+    /// Turn a YouTube URL into a lookup of its video, if we can use the API, so
+    /// every form of URL for a video shares a cache entry
+    fn normalized(self, config: &BotConfig) -> Self {
+        match self {
+            Self::Url(url, options)
+                if config.youtube.api_key.is_some() && config.url.allows_scheme(url.scheme()) =>
+            {
+                match extract_youtube_id(&url) {
+                    Some(id) => Self::YouTube(id, options.language),
+                    None => Self::Url(url, options),
+                }
+            }
+            cmd => cmd,
+        }
+    }
+}
+
+#[test]
+fn test_normalized_youtube() {
+    let mut config = BotConfig::default();
+    let url = |url: &str, language: Option<&str>| {
+        BotCommand::Url(
+            Url::parse(url).unwrap(),
+            UrlOptions {
+                policy: UrlPolicy::GlobalOnly,
+                language: language.map(str::to_string),
+            },
+        )
+    };
+
+    let short = url("https://youtu.be/a123456789Z", None);
+    assert_eq!(short.clone().normalized(&config), short);
+
+    config.youtube.api_key = Some("key".to_string());
+    let video = BotCommand::YouTube("a123456789Z".to_string(), None);
+    assert_eq!(short.normalized(&config), video);
+    assert_eq!(
+        url("https://www.youtube.com/watch?v=a123456789Z&t=42", None).normalized(&config),
+        video
+    );
+    assert_eq!(
+        url("https://youtube.com/shorts/a123456789Z", Some("de"))
+            .normalized(&config)
+            .to_string(),
+        "YouTube(a123456789Z, de)"
+    );
+
+    let channel = url("https://www.youtube.com/@example", None);
+    assert_eq!(channel.clone().normalized(&config), channel);
+    assert_eq!(video.clone().normalized(&config), video);
+}
+
+#[test]
+fn test_command_max_runtime() {
+    let mut config = CommandConfig::default();
+    config
+        .max_runtime_secs_per_kind
+        .insert("wolfram".to_string(), 30);
+
+    let url = BotCommand::Url(
+        Url::parse("https://example.com/").unwrap(),
+        BotConfig::default().url_options(None, ""),
+    );
+    let wolfram = BotCommand::Wolfram("1+1".to_string());
+    assert_eq!(config.max_runtime(url.kind()), Duration::from_secs(10));
+    assert_eq!(config.max_runtime(wolfram.kind()), Duration::from_secs(30));
+}
+
+#[tokio::test]
+async fn test_spawn_origin() {
+    let log = Logger::root(slog::Discard, o!());
+    let handler = CommandHandler::new(log, ConfigMonitor::fixed(BotConfig::default()));
+    let cmd = BotCommand::Wolfram("1+1".to_string());
+
+    let (res, origin) = handler.spawn(cmd.clone()).unwrap();
+    assert_eq!(origin, Origin::Fresh);
+    let (_, origin) = handler.spawn(cmd).unwrap();
+    assert_eq!(origin, Origin::Cache);
+    assert!(res.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_url_policy_restricts_fetch() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = "<title>Internal</title>";
+            let _ = stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .await;
+        }
+    });
+
+    let log = Logger::root(slog::Discard, o!());
+    let handler = CommandHandler::new(log, ConfigMonitor::fixed(BotConfig::default()));
+
+    let (res, _) = handler
+        .spawn(BotCommand::Url(
+            url.clone(),
+            UrlOptions {
+                policy: UrlPolicy::GlobalOnly,
+                language: None,
+            },
+        ))
+        .unwrap();
+    match &*res.await.unwrap() {
+        Err(e) => assert_eq!(e.to_string(), "Restricted IP"),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // Not answered from the restricted lookup's cache entry
+    let (res, origin) = handler
+        .spawn(BotCommand::Url(
+            url,
+            UrlOptions {
+                policy: UrlPolicy::Anywhere,
+                language: None,
+            },
+        ))
+        .unwrap();
+    assert_eq!(origin, Origin::Fresh);
+    match &*res.await.unwrap() {
+        Ok(Info::Url(info)) => assert_eq!(info.title.to_string(), "Internal"),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_config_update_clears_cache() {
+    let log = Logger::root(slog::Discard, o!());
+    let (monitor, updater) = ConfigMonitor::from_config(BotConfig::default());
+    let handler = CommandHandler::new(log, monitor);
+    let cmd = BotCommand::Wolfram("1+1".to_string());
+
+    let (res, _) = handler.spawn(cmd.clone()).unwrap();
+    res.await.unwrap();
+    assert_eq!(handler.spawn(cmd.clone()).unwrap().1, Origin::Cache);
+
+    let mut conf = BotConfig::default();
+    conf.command.cache_entries = 1;
+    conf.url.require_https = true;
+    assert!(updater.update(conf));
+    timeout(Duration::from_secs(5), async {
+        while !handler.cache.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(handler.spawn(cmd.clone()).unwrap().1, Origin::Fresh);
+
+    // The rebuilt cache holds a single entry, so another command evicts it
+    let (res, _) = handler
+        .spawn(BotCommand::Wolfram("2+2".to_string()))
+        .unwrap();
+    res.await.unwrap();
+    assert_eq!(handler.spawn(cmd).unwrap().1, Origin::Fresh);
+
+    // And the rebuilt client refuses plain http
+    let client = handler.client.lock().unwrap().clone();
+    let err = client.get("http://127.0.0.1:9/").send().await.unwrap_err();
+    assert!(err.is_builder());
+}
+
 impl std::fmt::Debug for CommandHandler {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CommandHandler")
@@ -85,27 +1197,159 @@ impl std::fmt::Debug for CommandHandler {
     }
 }
 
-fn cache_from_config(conf: &Arc<BotConfig>) -> LruCache<BotCommand, Response> {
-    LruCache::with_expiry_duration_and_capacity(
-        Duration::from_secs(conf.command.cache_time_secs as u64),
-        conf.command.cache_entries as usize,
-    )
+/// Entries expire according to their own `CacheEntry::expires`
+fn cache_from_config(conf: &Arc<BotConfig>) -> LruCache<BotCommand, CacheEntry> {
+    LruCache::with_capacity(conf.command.cache_entries as usize)
+}
+
+fn client_from_config(conf: &Arc<BotConfig>) -> reqwest::Client {
+    client_builder(conf)
+        .build()
+        .expect("Couldn't build HTTP client")
+}
+
+fn client_builder(conf: &Arc<BotConfig>) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::ClientBuilder::new()
+        .cookie_provider(Arc::new(cookie_jar(&conf.url)))
+        .pool_max_idle_per_host(conf.url.pool_max_idle_per_host as usize)
+        .https_only(conf.url.require_https);
+
+    // Otherwise connecting is bounded only by the request timeout
+    if let Some(secs) = conf.url.connect_timeout_secs {
+        builder = builder.connect_timeout(Duration::from_secs(secs as u64));
+    }
+
+    builder
+}
+
+/// A cookie jar seeded with any configured cookies
+fn cookie_jar(config: &UrlConfig) -> Jar {
+    let jar = Jar::default();
+    for (host, cookies) in &config.cookies {
+        if let Ok(url) = Url::parse(&format!("https://{}/", host)) {
+            for cookie in cookies.split(';').map(str::trim).filter(|c| !c.is_empty()) {
+                jar.add_cookie_str(&format!("{}; Domain={}; Path=/", cookie, host), &url);
+            }
+        }
+    }
+    jar
+}
+
+#[test]
+fn test_cookie_jar() {
+    use reqwest::cookie::CookieStore;
+
+    let mut config = UrlConfig::default();
+    config.cookies.insert(
+        "example.com".to_string(),
+        "CONSENT=YES+; euconsent=1;".to_string(),
+    );
+    let jar = cookie_jar(&config);
+    let cookies = |url: &str| {
+        jar.cookies(&Url::parse(url).unwrap()).map(|value| {
+            let mut cookies: Vec<_> = value
+                .to_str()
+                .unwrap()
+                .split("; ")
+                .map(String::from)
+                .collect();
+            cookies.sort();
+            cookies
+        })
+    };
+
+    let expected = Some(vec!["CONSENT=YES+".to_string(), "euconsent=1".to_string()]);
+    assert_eq!(cookies("https://example.com/article"), expected);
+    assert_eq!(cookies("http://news.example.com/"), expected);
+    assert_eq!(cookies("https://example.org/"), None);
+}
+
+#[tokio::test]
+async fn test_client_https_only() {
+    let mut conf = BotConfig::default();
+    conf.url.require_https = true;
+    let client = client_from_config(&Arc::new(conf));
+
+    // Refused before connecting, as are redirects to plain http
+    let err = client.get("http://127.0.0.1:9/").send().await.unwrap_err();
+    assert!(err.is_builder());
+}
+
+#[tokio::test]
+async fn test_client_https_only_redirect() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    };
+
+    // A self-signed certificate for 127.0.0.1, which the client is told to trust
+    let cert = include_bytes!("../testdata/localhost.crt.der");
+    let key = include_bytes!("../testdata/localhost.key.der");
+    let tls = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(&cert[..])],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(&key[..])),
+        )
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls));
+
+    let cleartext = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = format!("http://{}/", cleartext.local_addr().unwrap());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            if let Ok(mut stream) = acceptor.accept(stream).await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            target
+                        )
+                        .as_bytes(),
+                    )
+                    .await;
+                let _ = stream.shutdown().await;
+            }
+        }
+    });
+
+    let mut conf = BotConfig::default();
+    conf.url.require_https = true;
+    let client = client_builder(&Arc::new(conf))
+        .add_root_certificate(reqwest::Certificate::from_der(cert).unwrap())
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+
+    let err = client.get(&url).send().await.unwrap_err();
+    assert!(err.is_redirect());
+    // The redirect wasn't followed in the clear
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), cleartext.accept())
+            .await
+            .is_err()
+    );
 }
 
 impl CommandHandler {
     pub fn new(log: Logger, config: ConfigMonitor) -> Self {
         let conf = config.current();
         let (queue, queue_rx) = mpsc::channel(64);
+        let factoids = Arc::new(Factoids::load(&log, &conf.factoid));
         let handler = Self {
             log,
             config,
-            client: reqwest::ClientBuilder::new()
-                .cookie_store(true)
-                .pool_max_idle_per_host(1)
-                .build()
-                .expect("Couldn't build HTTP client"),
+            client: Arc::new(Mutex::new(client_from_config(&conf))),
+            hosts: HostLimiter::default(),
             queue,
             cache: Arc::new(Mutex::new(cache_from_config(&conf))),
+            stats: Arc::default(),
+            factoids,
         };
 
         handler
@@ -116,6 +1360,15 @@ impl CommandHandler {
 
     fn start(self, work: mpsc::Receiver<Work>, mut concurrency: u8) {
         let mut config = self.config.clone();
+        let client_settings = |conf: &BotConfig| {
+            (
+                conf.url.pool_max_idle_per_host,
+                conf.url.connect_timeout_secs,
+                conf.url.require_https,
+                conf.url.cookies.clone(),
+            )
+        };
+        let mut current_client = client_settings(&config.current());
         tokio::spawn(async move {
             let mut jobs = work.buffer_unordered(concurrency as usize);
             loop {
@@ -128,6 +1381,11 @@ impl CommandHandler {
                             concurrency = new_concurrency;
                         }
                         *cache = cache_from_config(&conf);
+                        // Rebuilding the client drops pooled connections and cookies
+                        if client_settings(&conf) != current_client {
+                            *self.client.lock().unwrap() = client_from_config(&conf);
+                            current_client = client_settings(&conf);
+                        }
                     },
                     Some(job) = jobs.next() => { let _ = job; },
                     else => { break; }
@@ -136,13 +1394,24 @@ impl CommandHandler {
         });
     }
 
-    pub fn spawn(&self, command: BotCommand) -> Option<Response> {
+    pub fn spawn(&self, command: BotCommand) -> Option<(Response, Origin)> {
+        let config = self.config.current();
+        let command = command.normalized(&config);
         let mut cache = self.cache.lock().unwrap();
         let log = self.log.new(o!("command" => command.to_string()));
+        self.stats.commands.fetch_add(1, Ordering::Relaxed);
 
-        if let Some(res) = cache.get(&command) {
-            info!(log, "cached");
-            return Some(res.clone());
+        match cache
+            .get(&command)
+            .map(|e| (e.expired(), e.response.clone()))
+        {
+            Some((false, res)) => {
+                info!(log, "cached");
+                self.stats.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return Some((res, Origin::Cache));
+            }
+            Some((true, _)) => info!(log, "expired"),
+            None => (),
         }
 
         info!(log, "execute");
@@ -150,43 +1419,141 @@ impl CommandHandler {
         let (tx, rx) = oneshot::channel::<Arc<Result<Info>>>();
         let rx = rx.shared();
 
-        cache.insert(command.clone(), rx.clone());
+        if let Some(max_bytes) = config.command.cache_max_bytes {
+            evict_to_budget(&mut cache, max_bytes as usize);
+        }
+
+        let entry = CacheEntry::new(
+            rx.clone(),
+            Duration::from_secs(config.command.cache_time_secs as u64),
+        );
+        cache.insert(command.clone(), entry.clone());
 
         let handler = self.clone();
-        let max_runtime =
-            Duration::from_secs(self.config.current().command.max_runtime_secs as u64);
+        let max_runtime = config.command.max_runtime(command.kind());
 
         let fut = async move {
             let res = match &command {
-                BotCommand::Url(url) => timeout(max_runtime, handler.handle_url(url)).await,
+                BotCommand::Url(url, options) => {
+                    timeout(max_runtime, handler.handle_url(url, options)).await
+                }
                 BotCommand::Omdb(kind, ref search) => {
                     timeout(max_runtime, handler.handle_omdb(kind, search)).await
                 }
+                BotCommand::OmdbId(id) => timeout(max_runtime, handler.handle_omdb_id(id)).await,
+                BotCommand::OmdbSearch(kind, ref search) => {
+                    timeout(max_runtime, handler.handle_omdb_search(kind, search)).await
+                }
                 BotCommand::Wolfram(query) => {
                     timeout(max_runtime, handler.handle_wolfram(query)).await
                 }
+                BotCommand::YouTube(id, language) => {
+                    timeout(max_runtime, handler.handle_youtube(id, language.as_deref())).await
+                }
             };
 
-            match res {
+            let res = match res {
                 Ok(res) => {
                     info!(log, "complete"; "result" => ?res);
-                    tx.send(Arc::new(res))
+                    res
                 }
                 Err(_) => {
                     info!(log, "timeout");
-                    tx.send(Arc::new(Err(anyhow!("Timed out"))))
+                    Err(anyhow!("Timed out"))
+                }
+            };
+
+            entry.size.store(
+                match &res {
+                    Ok(info) => info.estimated_size(),
+                    Err(e) => e.to_string().len(),
+                },
+                Ordering::Relaxed,
+            );
+
+            if let Err(e) = &res {
+                entry.shorten(error_ttl(e, &config.command));
+            }
+
+            // Where a URL redirects or declares itself canonical elsewhere,
+            // posting that URL later should find the same result
+            if let (BotCommand::Url(requested, options), Ok(Info::Url(info))) = (&command, &res) {
+                if *requested != info.url {
+                    let mut cache = handler.cache.lock().unwrap();
+                    cache.insert(
+                        BotCommand::Url(info.url.clone(), options.clone()),
+                        entry.clone(),
+                    );
+                    if let Some(max_bytes) = config.command.cache_max_bytes {
+                        evict_to_budget(&mut cache, max_bytes as usize);
+                    }
                 }
             }
+
+            tx.send(Arc::new(res))
         };
 
-        self.queue.clone().try_send(fut.boxed()).ok().map(|_| rx)
+        self.queue
+            .clone()
+            .try_send(fut.boxed())
+            .ok()
+            .map(|_| (rx, Origin::Fresh))
+    }
+
+    async fn handle_youtube(&self, id: &str, language: Option<&str>) -> Result<Info> {
+        let config = self.config.current();
+        youtube_lookup(id, &config.youtube, language)
+            .await
+            .map(Info::YouTube)
     }
 
     async fn handle_omdb(&self, kind: &str, search: &str) -> Result<Info> {
         let config = self.config.current();
 
+        let movie = match &config.omdb.api_key {
+            Some(key) if config.omdb.disambiguate => omdb::disambiguate(search, kind, key).await?,
+            Some(key) => omdb::search(search, kind, key).await?,
+            None => return Err(anyhow!("Unconfigured")),
+        };
+        Ok(Info::Movie(self.complete_movie(movie).await))
+    }
+
+    async fn handle_omdb_id(&self, id: &str) -> Result<Info> {
+        let config = self.config.current();
+
+        if let Some(key) = &config.omdb.api_key {
+            let movie = omdb::imdb_id(id, key).await?;
+            Ok(Info::Movie(self.complete_movie(movie).await))
+        } else {
+            Err(anyhow!("Unconfigured"))
+        }
+    }
+
+    /// Fill in what OMDb lacks from TMDb, or prefer TMDb's details, if
+    /// configured.  OMDb's result stands if TMDb fails.
+    async fn complete_movie(&self, movie: omdb::Movie) -> omdb::Movie {
+        let config = self.config.current();
+        if config.tmdb.api_key.is_none() || !(config.tmdb.primary || tmdb::incomplete(&movie)) {
+            return movie;
+        }
+
+        match tmdb::find(&movie.imdb_id, &config.tmdb).await {
+            Ok(Some(found)) => tmdb::merge(movie, found, config.tmdb.primary),
+            Ok(None) => movie,
+            Err(e) => {
+                debug!(self.log, "tmdb"; "status" => "failed", "imdb_id" => %movie.imdb_id, "error" => %e);
+                movie
+            }
+        }
+    }
+
+    async fn handle_omdb_search(&self, kind: &str, search: &str) -> Result<Info> {
+        let config = self.config.current();
+
         if let Some(key) = &config.omdb.api_key {
-            Ok(omdb::search(search, kind, key).await.map(Info::Movie)?)
+            omdb::candidates(search, kind, key)
+                .await
+                .map(Info::Candidates)
         } else {
             Err(anyhow!("Unconfigured"))
         }
@@ -195,21 +1562,42 @@ impl CommandHandler {
     async fn handle_wolfram(&self, query: &str) -> Result<Info> {
         let config = self.config.current();
 
-        if let Some(key) = &config.wolfram.app_id {
-            Ok(wolfram_query(query, key).await.map(Info::Wolfram)?)
+        if config.wolfram.app_id.is_some() {
+            wolfram_query(query, &config.wolfram)
+                .await
+                .map(Info::Wolfram)
         } else {
             Err(anyhow!("Unconfigured"))
         }
     }
 
-    async fn handle_url(&self, url: &Url) -> Result<Info> {
+    async fn handle_url(&self, url: &Url, options: &UrlOptions) -> Result<Info> {
         let config = self.config.current();
+        if !config.url.allows_scheme(url.scheme()) {
+            return Err(anyhow!("Disallowed scheme {}", url.scheme()));
+        }
+
+        let _permit = self
+            .hosts
+            .acquire(url.host_str().unwrap_or_default(), config.url.max_per_host)
+            .await;
+
+        if url.scheme() == "gemini" {
+            if config.gemini.enabled {
+                return gemini_lookup(url, &config.gemini, options.policy)
+                    .await
+                    .map(Info::Url);
+            }
+            return Err(anyhow!("Gemini disabled"));
+        }
+
         if let Some(key) = &config.omdb.api_key {
             if let Some("www.imdb.com") = url.host_str() {
                 if let Some(path) = url.path_segments().map(|c| c.collect::<Vec<_>>()) {
                     if path.len() > 1 && path[0] == "title" {
                         let imdb_id = path[1];
-                        return omdb::imdb_id(imdb_id, key).await.map(Info::Movie);
+                        let movie = omdb::imdb_id(imdb_id, key).await?;
+                        return Ok(Info::Movie(self.complete_movie(movie).await));
                     }
                 }
             }
@@ -219,63 +1607,72 @@ impl CommandHandler {
             if domain.ends_with(".wikipedia.org") {
                 let lang = domain.split('.').next().unwrap();
 
-                if let Some(path) = url.path_segments().map(|c| c.collect::<Vec<_>>()) {
-                    if path.len() > 1 && path[0] == "wiki" {
-                        let article = path[1];
-                        return self.fetch_wikipedia(lang, article).await.map(Info::Url);
-                    }
+                if let Some(article) = url.path().strip_prefix("/wiki/").filter(|a| !a.is_empty()) {
+                    return self.fetch_wikipedia(lang, article).await.map(Info::Url);
                 }
             }
         }
 
-        if config.youtube.api_key.is_some() {
-            if let Some(id) = extract_youtube_id(url) {
-                return youtube_lookup(&id, &config.youtube)
-                    .await
-                    .map(Info::YouTube);
-            }
-        }
-
-        self.fetch_url(url).await.map(Info::Url)
+        self.fetch_url(url, options).await.map(Info::Url)
     }
 
-    fn http_get(&self, url: &Url) -> reqwest::RequestBuilder {
+    fn http_get(&self, url: &Url, language: Option<&str>) -> reqwest::RequestBuilder {
         let config = self.config.current();
         let mut headers = HeaderMap::new();
-        headers.insert(ACCEPT_LANGUAGE, config.url.accept_language.clone());
+        headers.insert(
+            ACCEPT_LANGUAGE,
+            language
+                .and_then(|language| accept_language(language).ok())
+                .unwrap_or_else(|| config.url.accept_language.clone()),
+        );
         headers.insert(USER_AGENT, config.url.user_agent.clone());
 
-        self.client
+        let client = self.client.lock().unwrap().clone();
+        client
             .get(url.clone())
             .timeout(Duration::from_secs(config.url.timeout_secs as u64))
             .headers(headers)
     }
 
     async fn fetch_wikipedia(&self, lang: &str, article: &str) -> Result<UrlInfo> {
-        let url = Url::parse(&format!(
-            "https://{}.wikipedia.org/api/rest_v1/page/summary/{}",
-            lang, article
-        ))?;
+        let url = wikipedia_summary_url(lang, article)?;
 
-        let wiki = self.http_get(&url).send().await?.json::<Wiki>().await?;
+        let res = self.http_get(&url, None).send().await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(anyhow!("No such article"));
+        }
+        check_status(res.status(), res.headers())?;
+
+        let wiki = res.json::<Wiki>().await?;
+        let config = self.config.current();
 
         Ok(UrlInfo {
             url,
+            desc: wiki.summary(&config.wikipedia).map(IrcString::from),
             title: wiki.title.into(),
-            desc: Some(wiki.extract.into()),
         })
     }
 
-    async fn fetch_url(&self, url: &Url) -> Result<UrlInfo> {
+    async fn fetch_url(&self, url: &Url, options: &UrlOptions) -> Result<UrlInfo> {
         let config = self.config.current();
 
-        let mut res = self.http_get(url).send().await?;
+        let mut res = self
+            .http_get(url, options.language.as_deref())
+            .send()
+            .await?;
 
-        if !res.status().is_success() {
-            return Err(anyhow!("Status {}", res.status()));
+        if config.url.debug_responses {
+            let content_type = res
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|ct| ct.to_str().ok())
+                .unwrap_or_default();
+            debug!(self.log, "response"; "url" => %res.url(), "status" => res.status().as_u16(), "content_type" => content_type, "content_length" => res.content_length());
         }
 
-        if config.url.globally_routable_only
+        check_status(res.status(), res.headers())?;
+
+        if options.policy == UrlPolicy::GlobalOnly
             && res
                 .remote_addr()
                 .map(|addr| !ip_rfc::global(&addr.ip()))
@@ -295,44 +1692,110 @@ impl CommandHandler {
             }
         }
 
-        let byte_limit = config.url.max_kb as usize * 1024;
-        let mut chunk_limit = config.url.max_chunks;
-        let mut buf = Vec::with_capacity(byte_limit * 2);
-
+        let mut body = BodyReader::new(&config.url);
+        let mut chunks = 0;
         while let Some(chunk) = res.chunk().await? {
-            buf.extend(chunk);
-            chunk_limit -= 1;
-
-            if buf.len() >= byte_limit || chunk_limit == 0 {
+            chunks += 1;
+            if body.push(&chunk) {
                 break;
             }
         }
 
-        let buf = String::from_utf8_lossy(&buf);
+        if config.url.debug_responses {
+            debug!(self.log, "body"; "url" => %res.url(), "chunks" => chunks, "bytes" => body.buf.len());
+        }
+
+        let content_type = res
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok());
+        let buf = decode_body(&body.buf, content_type);
+        let buf = if config.url.parse_head_only {
+            head_region(&buf)
+        } else {
+            &buf
+        };
 
-        let fragment = Html::parse_document(&buf);
-        let title_selector = Selector::parse(r#"title"#).unwrap();
-        let description_selector = Selector::parse(r#"meta[name="description"], meta[name="twitter:description"], meta[property="og:description"]"#).unwrap();
+        let fragment = Html::parse_document(buf);
 
-        let title = fragment
-            .select(&title_selector)
-            .next()
-            .map(|n| IrcString::from(n.text().collect::<String>()))
-            .filter(|s| !s.is_empty())
+        let title = select_first(&fragment, &config.url.title_selectors)
             .ok_or_else(|| anyhow!("No title"))?;
+        let desc = select_first(&fragment, &config.url.description_selectors);
 
-        let desc = fragment
-            .select(&description_selector)
-            .next()
-            .and_then(|n| n.value().attr("content"))
-            .map(html_escape::decode_html_entities)
-            .map(IrcString::from)
-            .filter(|s| !s.is_empty());
+        let meta = if config.url.strip_site_name || config.url.debug_metadata {
+            PageMetadata::extract(&fragment)
+        } else {
+            PageMetadata::default()
+        };
 
-        Ok(UrlInfo {
-            url: res.url().clone(),
-            title,
-            desc,
-        })
+        if config.url.debug_metadata {
+            debug!(self.log, "metadata"; "url" => %res.url(), "icon" => ?meta.icon, "theme_color" => ?meta.theme_color, "site_name" => ?meta.site_name);
+        }
+
+        let title = match (config.url.strip_site_name, &meta.site_name) {
+            (true, Some(site_name)) => strip_site_name(&title, site_name)
+                .map(IrcString::from)
+                .unwrap_or(title),
+            _ => title,
+        };
+
+        let url = if config.url.canonical_link_header {
+            canonical_link(res.url(), res.headers()).unwrap_or_else(|| res.url().clone())
+        } else {
+            res.url().clone()
+        };
+
+        Ok(UrlInfo { url, title, desc })
+    }
+}
+
+/// The target of a `Link: <...>; rel="canonical"` header, if it's on the same
+/// host, so a page can't pass itself off as somewhere else
+fn canonical_link(url: &Url, headers: &HeaderMap) -> Option<Url> {
+    lazy_static! {
+        static ref LINK: Regex = Regex::new(r"<([^>]*)>([^<]*)").unwrap();
+        static ref REL: Regex = Regex::new(r#"(?i);\s*rel\s*=\s*"?([^";,]*)"?"#).unwrap();
     }
+
+    headers
+        .get_all(reqwest::header::LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| LINK.captures_iter(value))
+        .find(|link| {
+            REL.captures(&link[2]).is_some_and(|rel| {
+                rel[1]
+                    .split_ascii_whitespace()
+                    .any(|rel| rel.eq_ignore_ascii_case("canonical"))
+            })
+        })
+        .and_then(|link| url.join(link[1].trim()).ok())
+        .filter(|canonical| {
+            matches!(canonical.scheme(), "http" | "https") && canonical.host() == url.host()
+        })
+}
+
+#[test]
+fn test_canonical_link() {
+    let url = Url::parse("https://example.com/article?utm_source=irc").unwrap();
+    let canonical = |link: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::LINK, link.parse().unwrap());
+        canonical_link(&url, &headers).map(String::from)
+    };
+
+    assert_eq!(
+        canonical(r#"<https://example.com/next>; rel="next", </article>; rel="canonical""#),
+        Some("https://example.com/article".to_string())
+    );
+    assert_eq!(
+        canonical("<https://example.com/a>; rel=canonical"),
+        Some("https://example.com/a".to_string())
+    );
+    assert_eq!(canonical(r#"<https://example.com/next>; rel="next""#), None);
+    assert_eq!(
+        canonical(r#"<https://elsewhere.example/>; rel="canonical""#),
+        None
+    );
+    assert_eq!(canonical_link(&url, &HeaderMap::new()), None);
 }
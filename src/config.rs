@@ -1,18 +1,29 @@
 use std::{
     collections::HashMap,
     convert::TryFrom,
+    net::SocketAddr,
     path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use anyhow::{anyhow, Result};
-use irc::client::prelude::Config;
-use regex::RegexSet;
+use anyhow::{anyhow, Context, Result};
+use chrono::{
+    format::{Item, StrftimeItems},
+    FixedOffset,
+};
+use irc::client::prelude::{Config, Message, Prefix};
+use lazy_static::lazy_static;
+use num_format::Locale;
+use regex::{Regex, RegexSet};
 use reqwest::header::HeaderValue;
+use scraper::Selector;
 use serde::{Deserialize, Deserializer};
 use slog::{crit, error, info, warn, Logger};
 use tokio::{io::AsyncReadExt, sync::watch};
 
+use crate::irc_string::LINE_BYTES;
+
 #[derive(Debug, Clone)]
 pub struct ConfigMonitor(watch::Receiver<Arc<BotConfig>>);
 
@@ -22,45 +33,481 @@ pub struct ConfigUpdater(Arc<Mutex<Option<watch::Sender<Arc<BotConfig>>>>>);
 #[derive(Default, Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct BotConfig {
+    /// Maximum simultaneous network connections, unlimited by default
+    pub max_networks: Option<u16>,
+    /// How long to spend finishing pending output and quitting when shutting
+    /// down, 2 seconds by default
+    pub shutdown_grace_secs: Option<u8>,
+    /// Another file, relative to this one, whose settings take precedence
+    /// over ours, so this one needn't contain API keys and passwords
+    pub secrets_file: Option<PathBuf>,
     pub command: CommandConfig,
     pub template: TemplateConfig,
     pub url: UrlConfig,
     pub twitter: TwitterConfig,
     pub omdb: OmdbConfig,
+    pub tmdb: TmdbConfig,
     pub youtube: YouTubeConfig,
     pub wolfram: WolframConfig,
+    pub gemini: GeminiConfig,
+    pub wikipedia: WikipediaConfig,
+    pub factoid: FactoidConfig,
+    pub api: ApiConfig,
     pub defaults: Config,
-    pub network: HashMap<String, Config>,
+    /// Settings shared by several networks, e.g. members of a relay mesh
+    pub group: HashMap<String, NetworkConfig>,
+    pub network: HashMap<String, NetworkConfig>,
+    /// Messages to post on a schedule
+    pub schedule: Vec<ScheduleConfig>,
+    /// Named sets of channels by network, for admins to message at once
+    pub broadcast: HashMap<String, HashMap<String, Vec<String>>>,
+    /// Named locales, chosen with `locale = "<name>"` on a network or channel
+    pub locale: HashMap<String, LocaleConfig>,
 }
 
-#[derive(Default, Debug, Deserialize, Clone)]
+/// A recurring message, under `[[schedule]]`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub network: String,
+    pub channel: String,
+    /// When to post, in UTC, as `sec min hour day-of-month month day-of-week [year]`
+    #[serde(deserialize_with = "parse_cron")]
+    pub cron: cron::Schedule,
+    /// The message, with `{channel}` replaced
+    pub message: String,
+}
+
+/// Per-network settings: the `irc` crate's own `Config` plus our extensions
+#[derive(Default, Debug, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    #[serde(flatten)]
+    pub irc: Config,
+    /// Send our own PING this often once registered, logging the round-trip time
+    pub ping_interval_secs: Option<u32>,
+    /// IRCv3 capabilities to request whenever the server offers them
+    pub capabilities: Vec<String>,
+    /// Connect and join channels, but never process commands or URLs
+    pub passive: bool,
+    /// Also process commands and URLs sent to channels via NOTICE
+    pub process_notices: bool,
+    /// Never respond to these nicks, typically other bots
+    pub ignore_nicks: Vec<String>,
+    /// Inherit any settings not given here from this `[group.<name>]`
+    pub group: Option<String>,
+    /// Don't preview a URL another member of our group previewed in the same
+    /// channel within this many seconds
+    pub group_dedupe_secs: u32,
+    /// Hostmasks like `nick!user@host`, with `*` and `?` wildcards, allowed
+    /// to use admin commands
+    pub admins: Vec<String>,
+    /// Greet people who join while we're present when they first speak, with
+    /// `{nick}` and `{channel}` replaced
+    pub greeting: Option<String>,
+    /// Channels in which not to greet anyone
+    pub greeting_disabled_channels: Vec<String>,
+    /// Bridge bots relaying messages from elsewhere
+    pub relay: RelayConfig,
+    /// Pace our messages more conservatively for this many seconds after
+    /// registering
+    pub warmup_secs: u32,
+    /// How many messages to send per minute while warming up, 6 by default
+    pub warmup_messages_per_minute: Option<u32>,
+    /// User modes, e.g. "o", which exempt us from the network's flood
+    /// protection.  Warmup pacing is skipped while we hold any of them.
+    pub flood_exempt_modes: String,
+    /// WALLOPS and NOTICEs from servers
+    pub server_notices: ServerNoticeConfig,
+    /// Identify to services ourselves, joining channels only once they
+    /// recognise us
+    pub nickserv: Option<NickServConfig>,
+    /// Settings for individual channels
+    pub channel: HashMap<String, ChannelConfig>,
+    /// Also join channels matching these wildcard patterns when invited, and
+    /// respond in them.  `*` means any channel anyone invites us to.
+    pub channel_patterns: Vec<String>,
+    /// Appended to everything we send, and any message bearing it ignored, so
+    /// cooperating instances sharing channels don't respond to each other
+    pub fingerprint: Option<String>,
+    /// Read the server password, sent as PASS, from this environment variable
+    pub password_env: Option<String>,
+    /// Override url.globally_routable_only, e.g. for a trusted internal network
+    pub globally_routable_only: Option<bool>,
+    /// The `[locale.<name>]` for this network's channels, unless they choose
+    /// their own
+    pub locale: Option<String>,
+}
+
+/// Per-channel settings, under `[network.<name>.channel."#channel"]`
+#[derive(Default, Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct ChannelConfig {
+    /// Truncate URL titles and descriptions to this many bytes
+    pub max_preview_bytes: Option<u16>,
+    /// The `[locale.<name>]` for this channel
+    pub locale: Option<String>,
+    /// Override twitter.rewrite_to_nitter
+    pub rewrite_to_nitter: Option<bool>,
+    /// Override command.prefix, e.g. where another bot already uses it
+    pub prefix: Option<String>,
+}
+
+/// Surfacing server announcements, e.g. for bots that are IRC operators
+#[derive(Default, Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct ServerNoticeConfig {
+    /// Log them at info level
+    pub log: bool,
+    /// Relay them to one of our channels, subject to its rate limit
+    pub channel: Option<String>,
+}
+
+/// Identifying to services, under `[network.<name>.nickserv]`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct NickServConfig {
+    pub password: String,
+    /// If someone else has our nick, reclaim it with GHOST and RELEASE
+    pub ghost: bool,
+    /// The services nick to talk to
+    pub service: String,
+    /// Join channels anyway if identification isn't confirmed in this many
+    /// seconds
+    pub identify_timeout_secs: u8,
+}
+
+impl Default for NickServConfig {
+    fn default() -> Self {
+        Self {
+            password: String::new(),
+            ghost: false,
+            service: "NickServ".to_string(),
+            identify_timeout_secs: 15,
+        }
+    }
+}
+
+/// Recognising messages relayed by bridge bots, e.g. `<realuser> text`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct RelayConfig {
+    /// Nicks of the relay bots
+    pub nicks: Vec<String>,
+    /// Matches relayed messages, capturing the real author as `nick` and their
+    /// message as `text`
+    #[serde(deserialize_with = "parse_relay_format")]
+    pub format: RelayFormat,
+    /// Ignore relayed messages entirely rather than processing them as if
+    /// sent by the real author
+    pub skip: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayFormat(pub Regex);
+
+impl PartialEq for RelayFormat {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            nicks: vec![],
+            format: RelayFormat(Regex::new(r"^<(?P<nick>[^>\s]+)> (?P<text>.*)$").unwrap()),
+            skip: false,
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn is_relay(&self, nick: &str) -> bool {
+        self.nicks
+            .iter()
+            .any(|relay| relay.eq_ignore_ascii_case(nick))
+    }
+}
+
+impl NetworkConfig {
+    pub fn ignores_nick(&self, nick: &str) -> bool {
+        self.ignore_nicks
+            .iter()
+            .any(|ignored| ignored.eq_ignore_ascii_case(nick))
+    }
+
+    pub fn greets(&self, channel: &str) -> bool {
+        self.greeting.is_some()
+            && !self.passive
+            && !self
+                .greeting_disabled_channels
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(channel))
+    }
+
+    /// Whether to respond in a channel: one we're configured for, or one
+    /// matching a pattern
+    pub fn serves_channel(&self, channel: &str) -> bool {
+        self.irc
+            .channels
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(channel))
+            || (channel.starts_with(['#', '&', '+', '!'])
+                && self
+                    .channel_patterns
+                    .iter()
+                    .any(|pattern| mask_matches(pattern, channel)))
+    }
+
+    pub fn has_fingerprint(&self, content: &str) -> bool {
+        self.fingerprint
+            .as_deref()
+            .is_some_and(|fingerprint| content.contains(fingerprint))
+    }
+
+    /// Which addresses lookups for URLs posted on this network may reach
+    pub fn url_policy(&self, config: &UrlConfig) -> UrlPolicy {
+        match self.globally_routable_only {
+            Some(only) => UrlPolicy::from_routable_only(only),
+            None => config.policy(),
+        }
+    }
+
+    /// Settings for a channel, if it has any
+    pub fn channel_config(&self, channel: &str) -> Option<&ChannelConfig> {
+        self.channel
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(channel))
+            .map(|(_, c)| c)
+    }
+
+    /// The key to join a channel with, if configured in channel_keys.  Servers
+    /// may name a channel in a different case to our configuration.
+    pub fn channel_key(&self, channel: &str) -> Option<&str> {
+        self.irc
+            .channel_keys
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(channel))
+            .map(|(_, key)| key.as_str())
+    }
+
+    /// How many bytes of a URL title or description to show in a channel
+    pub fn preview_bytes(&self, channel: &str) -> usize {
+        self.channel_config(channel)
+            .and_then(|c| c.max_preview_bytes)
+            .map_or(LINE_BYTES, |bytes| bytes as usize)
+    }
+
+    /// The prefix marking commands in a channel.  URLs are found the same
+    /// way whatever it is.
+    pub fn command_prefix<'a>(&'a self, channel: &str, config: &'a CommandConfig) -> &'a str {
+        self.channel_config(channel)
+            .and_then(|c| c.prefix.as_deref())
+            .unwrap_or(&config.prefix)
+    }
+
+    /// Where to look up Twitter links posted in a channel
+    pub fn twitter_links<'a>(&self, channel: &str, config: &'a TwitterConfig) -> TwitterLinks<'a> {
+        config.rewriting(
+            self.channel_config(channel)
+                .and_then(|c| c.rewrite_to_nitter)
+                .unwrap_or(config.rewrite_to_nitter),
+        )
+    }
+
+    /// Whether a message's sender matches any admin hostmask
+    pub fn is_admin(&self, message: &Message) -> bool {
+        match &message.prefix {
+            Some(Prefix::Nickname(nick, user, host)) => {
+                let source = format!("{}!{}@{}", nick, user, host);
+                self.admins.iter().any(|mask| mask_matches(mask, &source))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Case-insensitively match an IRC hostmask with `*` and `?` wildcards
+fn mask_matches(mask: &str, source: &str) -> bool {
+    fn matches(mask: &[u8], source: &[u8]) -> bool {
+        match (mask.split_first(), source.split_first()) {
+            (None, None) => true,
+            (Some((b'*', rest)), _) => {
+                matches(rest, source) || (!source.is_empty() && matches(mask, &source[1..]))
+            }
+            (Some((m, mrest)), Some((s, srest))) => {
+                (*m == b'?' || m.eq_ignore_ascii_case(s)) && matches(mrest, srest)
+            }
+            _ => false,
+        }
+    }
+
+    matches(mask.as_bytes(), source.as_bytes())
+}
+
+#[test]
+fn test_serves_channel() {
+    let mut netconf = NetworkConfig::default();
+    netconf.irc.channels = vec!["#annobot".to_string()];
+    assert!(netconf.serves_channel("#annobot"));
+    assert!(netconf.serves_channel("#AnnoBot"));
+    assert!(!netconf.serves_channel("#elsewhere"));
+    assert!(!netconf.serves_channel("annobot"));
+
+    netconf.channel_patterns = vec!["*".to_string()];
+    assert!(netconf.serves_channel("#annobot"));
+    assert!(netconf.serves_channel("#elsewhere"));
+    assert!(!netconf.serves_channel("annobot"));
+
+    netconf.channel_patterns = vec!["#rust-*".to_string()];
+    assert!(netconf.serves_channel("#Rust-Offtopic"));
+    assert!(!netconf.serves_channel("#elsewhere"));
+}
+
+#[test]
+fn test_mask_matches() {
+    assert!(mask_matches(
+        "freaky!*@*.example",
+        "Freaky!~tom@host.example"
+    ));
+    assert!(mask_matches("*!*@trusted", "anyone!user@trusted"));
+    assert!(mask_matches("nick!us?r@host", "nick!user@host"));
+    assert!(!mask_matches(
+        "freaky!*@*.example",
+        "freaky!tom@example.com"
+    ));
+    assert!(!mask_matches("freaky", "freaky!tom@host"));
+}
+
+#[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct TwitterConfig {
     pub bearer_token: Option<String>,
+    /// A Nitter instance, e.g. "nitter.example", at which to look up Twitter
+    /// links instead.  Links are left alone if this isn't set.
+    pub nitter_host: Option<String>,
+    /// Rewrite Twitter links to nitter_host before looking them up, unless a
+    /// channel says otherwise
+    pub rewrite_to_nitter: bool,
+}
+
+impl Default for TwitterConfig {
+    fn default() -> Self {
+        Self {
+            bearer_token: None,
+            nitter_host: None,
+            rewrite_to_nitter: true,
+        }
+    }
+}
+
+impl TwitterConfig {
+    pub fn links(&self) -> TwitterLinks<'_> {
+        self.rewriting(self.rewrite_to_nitter)
+    }
+
+    fn rewriting(&self, rewrite: bool) -> TwitterLinks<'_> {
+        match (&self.nitter_host, rewrite) {
+            (Some(host), true) => TwitterLinks::Nitter(host),
+            _ => TwitterLinks::Original,
+        }
+    }
+}
+
+/// Where Twitter links posted in a channel are looked up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwitterLinks<'a> {
+    /// At the given Nitter instance
+    Nitter(&'a str),
+    /// As posted
+    Original,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct UrlConfig {
     pub max_per_message: u8,
+    /// Note on the last preview when a message had more URLs than this
+    pub note_excess_urls: bool,
+    /// Only preview URLs making up at least this percentage of a message
+    pub min_url_share_percent: u8,
     pub max_kb: u16,
     pub max_chunks: u16,
     pub timeout_secs: u8,
+    pub connect_timeout_secs: Option<u8>,
+    pub pool_max_idle_per_host: u8,
+    pub max_per_host: u8,
     pub globally_routable_only: bool,
     pub scheme_required: bool,
+    pub require_https: bool,
+    pub detector: UrlDetector,
+    pub strip_trailing_punctuation: bool,
+    pub strip_unbalanced_delimiters: bool,
+    pub allowed_schemes: Vec<String>,
+    pub ignore_fragments: bool,
+    pub per_user_dedupe_secs: u32,
     pub include_description: bool,
+    pub combine_lines: bool,
+    pub combined_separator: String,
+    pub report_status_on_command: bool,
+    pub parse_head_only: bool,
+    /// Show and cache pages under the URL from a `Link: <...>; rel="canonical"`
+    /// header on the same host
+    pub canonical_link_header: bool,
+    /// Strip a trailing " - Site Name" or " | Site Name" from titles when it
+    /// matches the page's og:site_name
+    pub strip_site_name: bool,
+    pub debug_metadata: bool,
+    /// Log each response's status, content type and length, and how much of
+    /// it we read, at debug level
+    pub debug_responses: bool,
+    pub history_entries: u8,
     #[serde(deserialize_with = "parse_header_value")]
     pub user_agent: HeaderValue,
     #[serde(deserialize_with = "parse_header_value")]
     pub accept_language: HeaderValue,
+    /// Cookies to send to each host and its subdomains, e.g. to get past
+    /// consent interstitials, as `name=value; other=value`
+    pub cookies: HashMap<String, String>,
     #[serde(deserialize_with = "parse_regex_set")]
     pub ignore_url_regex: RegexSet,
+    /// Where to find a page's title, in order of preference
+    #[serde(deserialize_with = "parse_selectors")]
+    pub title_selectors: Vec<Selector>,
+    /// Where to find a page's description, in order of preference
+    #[serde(deserialize_with = "parse_selectors")]
+    pub description_selectors: Vec<Selector>,
+}
+
+/// How URLs are found in messages
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UrlDetector {
+    /// Twitter's heuristics, which also find bare domains
+    EggMode,
+    /// Only text starting with an explicit scheme
+    Regex,
+    /// Anything either detector finds
+    Both,
 }
 
 #[derive(Default, Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct OmdbConfig {
     pub api_key: Option<String>,
+    pub disambiguate: bool,
+}
+
+/// TMDb, to fill gaps in OMDb's details or localise them
+#[derive(Default, Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct TmdbConfig {
+    pub api_key: Option<String>,
+    /// Ask for details in this language, e.g. "de-DE", rather than "en-US"
+    pub language: Option<String>,
+    /// Prefer TMDb's title and plot to OMDb's, rather than only filling gaps
+    pub primary: bool,
 }
 
 #[derive(Default, Debug, Deserialize, Clone)]
@@ -70,20 +517,130 @@ pub struct YouTubeConfig {
     pub lang: Option<String>,
 }
 
-#[derive(Default, Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct WolframConfig {
     pub app_id: Option<String>,
+    pub min_pods: usize,
+    pub max_kb: u16,
+    /// Offer Wolfram's suggestion when it can't interpret a query
+    pub show_suggestions: bool,
+}
+
+impl Default for WolframConfig {
+    fn default() -> Self {
+        Self {
+            app_id: None,
+            min_pods: 1,
+            max_kb: 256,
+            show_suggestions: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct WikipediaConfig {
+    pub include_description: bool,
+    pub include_coordinates: bool,
+    pub max_sentences: u8,
+}
+
+impl Default for WikipediaConfig {
+    fn default() -> Self {
+        Self {
+            include_description: false,
+            include_coordinates: false,
+            max_sentences: 2,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct GeminiConfig {
+    pub enabled: bool,
+    pub max_kb: u16,
+}
+
+impl Default for GeminiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_kb: 64,
+        }
+    }
+}
+
+#[derive(Default, Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct ApiConfig {
+    /// Serve lookups over HTTP on this address.  Only read at startup.
+    pub listen: Option<SocketAddr>,
+    /// Require `Authorization: Bearer <token>`
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields, default)]
+pub struct FactoidConfig {
+    pub enabled: bool,
+    /// Where to keep learned factoids across restarts.  Only read at startup.
+    pub path: Option<PathBuf>,
+    pub max_per_channel: u16,
+    pub max_key_bytes: u8,
+    pub max_value_bytes: u16,
+}
+
+impl Default for FactoidConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            max_per_channel: 100,
+            max_key_bytes: 32,
+            max_value_bytes: 300,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct CommandConfig {
     pub max_concurrency: u8,
+    pub max_pending_per_connection: u16,
     pub max_runtime_secs: u8,
+    pub max_runtime_secs_per_kind: HashMap<String, u8>,
     pub cache_time_secs: u32,
+    pub error_cache_time_secs: Option<u32>,
+    pub report_rate_limits: bool,
+    /// Most lines to send in response to any one message, across every
+    /// command and URL it triggers
+    pub max_lines_per_message: u8,
+    /// Say so when further lines are withheld because of max_lines_per_message
+    pub note_truncated_output: bool,
     pub cache_entries: u32,
+    pub cache_max_bytes: Option<u32>,
     pub prefix: String,
+    /// Append a JSON line for every command run to this file
+    pub audit_log: Option<PathBuf>,
+    pub with_urls: CommandWithUrls,
+    /// Further commands, by name, to restrict to each network's admins
+    pub admin_only: Vec<String>,
+    /// Only run these kinds of command in the listed channels
+    pub channels_per_kind: HashMap<String, Vec<String>>,
+    /// Tell the channel when a command is dropped because of channels_per_kind
+    pub restricted_notice: Option<String>,
+}
+
+/// What to do with URLs in a message that is also a recognised command
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandWithUrls {
+    /// Run the command and ignore any URLs
+    CommandOrUrls,
+    /// Run the command and preview any URLs too
+    Both,
 }
 
 fn parse_header_value<'de, D>(d: D) -> Result<HeaderValue, D::Error>
@@ -94,6 +651,47 @@ where
     HeaderValue::try_from(s).map_err(serde::de::Error::custom)
 }
 
+fn parse_cron<'de, D>(d: D) -> Result<cron::Schedule, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    s.parse()
+        .map_err(|e| serde::de::Error::custom(format!("invalid cron expression {:?}: {}", s, e)))
+}
+
+fn parse_selectors<'de, D>(d: D) -> Result<Vec<Selector>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    <Vec<String>>::deserialize(d)?
+        .iter()
+        .map(|s| {
+            Selector::parse(s)
+                .map_err(|e| serde::de::Error::custom(format!("invalid selector {:?}: {}", s, e)))
+        })
+        .collect()
+}
+
+fn parse_relay_format<'de, D>(d: D) -> Result<RelayFormat, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let re = Regex::new(&String::deserialize(d)?).map_err(serde::de::Error::custom)?;
+    if re
+        .capture_names()
+        .flatten()
+        .filter(|name| *name == "nick" || *name == "text")
+        .count()
+        != 2
+    {
+        return Err(serde::de::Error::custom(
+            "relay format must capture nick and text",
+        ));
+    }
+    Ok(RelayFormat(re))
+}
+
 fn parse_regex_set<'de, D>(d: D) -> Result<RegexSet, D::Error>
 where
     D: Deserializer<'de>,
@@ -106,79 +704,948 @@ impl Default for UrlConfig {
     fn default() -> Self {
         Self {
             max_per_message: 3,
+            note_excess_urls: false,
+            min_url_share_percent: 0,
             timeout_secs: 10,
+            connect_timeout_secs: None,
+            pool_max_idle_per_host: 1,
+            max_per_host: 2,
             max_kb: 256,
             max_chunks: 256,
             globally_routable_only: true,
             scheme_required: false,
+            require_https: false,
+            detector: UrlDetector::EggMode,
+            strip_trailing_punctuation: true,
+            strip_unbalanced_delimiters: true,
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            ignore_fragments: true,
+            per_user_dedupe_secs: 0,
             include_description: true,
+            combine_lines: false,
+            combined_separator: " - ".to_string(),
+            report_status_on_command: false,
+            parse_head_only: true,
+            canonical_link_header: false,
+            strip_site_name: false,
+            debug_metadata: false,
+            debug_responses: false,
+            history_entries: 10,
             user_agent: HeaderValue::from_static(
                 "Mozilla/5.0 (FreeBSD 14.0; FreeBSD; x64; rv:81) Gecko/20100101 annoirc/81",
             ),
             accept_language: HeaderValue::from_static("en,*;q=0.5"),
+            cookies: HashMap::new(),
             ignore_url_regex: RegexSet::empty(),
+            title_selectors: vec![Selector::parse("title").unwrap()],
+            description_selectors: [
+                r#"meta[name="description"]"#,
+                r#"meta[name="twitter:description"]"#,
+                r#"meta[property="og:description"]"#,
+            ]
+            .iter()
+            .map(|s| Selector::parse(s).unwrap())
+            .collect(),
+        }
+    }
+}
+
+/// How responses are localised, under `[locale.<name>]`.  `[locale.default]`
+/// applies wherever no other locale is chosen, and otherwise we use English
+/// numbers, dates as given, and the `[url]` and `[youtube]` languages.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields, default)]
+pub struct LocaleConfig {
+    /// Group digits as in this locale, e.g. "de" for 1.234.567
+    #[serde(deserialize_with = "parse_number_locale")]
+    pub numbers: Locale,
+    /// Show absolute dates at this offset from UTC, e.g. "+01:00", rather than
+    /// in the offset they came with
+    #[serde(deserialize_with = "parse_utc_offset")]
+    pub utc_offset: Option<FixedOffset>,
+    /// Ask for pages and YouTube details in this language, e.g. "de",
+    /// overriding url.accept_language and youtube.lang
+    pub language: Option<String>,
+}
+
+impl Default for LocaleConfig {
+    fn default() -> Self {
+        Self {
+            numbers: Locale::en,
+            utc_offset: None,
+            language: None,
         }
     }
 }
 
+lazy_static! {
+    static ref DEFAULT_LOCALE: LocaleConfig = LocaleConfig::default();
+}
+
+fn parse_number_locale<'de, D>(d: D) -> Result<Locale, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    Locale::from_name(&s).map_err(|_| serde::de::Error::custom(format!("unknown locale {:?}", s)))
+}
+
+fn parse_utc_offset<'de, D>(d: D) -> Result<Option<FixedOffset>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    s.parse().map(Some).map_err(|_| {
+        serde::de::Error::custom(format!(
+            "invalid UTC offset {:?}, expected e.g. \"+01:00\"",
+            s
+        ))
+    })
+}
+
+/// The Accept-Language header to send when asking for a language
+pub fn accept_language(language: &str) -> Result<HeaderValue> {
+    if language.is_empty()
+        || !language
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    {
+        return Err(anyhow!("invalid language {:?}", language));
+    }
+    Ok(HeaderValue::try_from(format!("{},*;q=0.5", language))?)
+}
+
+/// How a URL is looked up, which may differ between networks and channels.
+/// Part of a lookup's cache key, so each sees only results fetched its way.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UrlOptions {
+    pub policy: UrlPolicy,
+    /// Preferred language, from the channel's locale
+    pub language: Option<String>,
+}
+
+/// Which addresses URL lookups may connect to.  Part of a lookup's cache key,
+/// so a restricted network never sees results fetched for a trusted one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum UrlPolicy {
+    /// Only globally routable addresses
+    GlobalOnly,
+    /// Anywhere, including localhost and private networks
+    Anywhere,
+}
+
+impl UrlPolicy {
+    fn from_routable_only(only: bool) -> Self {
+        if only {
+            Self::GlobalOnly
+        } else {
+            Self::Anywhere
+        }
+    }
+}
+
+#[test]
+fn test_url_policy() {
+    let mut url = UrlConfig::default();
+    let mut netconf = NetworkConfig::default();
+    assert_eq!(netconf.url_policy(&url), UrlPolicy::GlobalOnly);
+
+    netconf.globally_routable_only = Some(false);
+    assert_eq!(netconf.url_policy(&url), UrlPolicy::Anywhere);
+
+    url.globally_routable_only = false;
+    netconf.globally_routable_only = Some(true);
+    assert_eq!(netconf.url_policy(&url), UrlPolicy::GlobalOnly);
+    netconf.globally_routable_only = None;
+    assert_eq!(netconf.url_policy(&url), UrlPolicy::Anywhere);
+}
+
+#[test]
+fn test_password_env() {
+    let network = "[network.one]\nserver = \"irc.example\"\nnickname = \"bot\"\n";
+    let mut config = BotConfig::parse(network).unwrap();
+    config.network.get_mut("one").unwrap().password_env = Some("ONE_PASSWORD".to_string());
+
+    let env = |var: &str| match var {
+        "ONE_PASSWORD" => Ok("hunter2".to_string()),
+        _ => Err(std::env::VarError::NotPresent),
+    };
+    config.read_password_env(env).unwrap();
+    assert_eq!(config.network["one"].irc.password(), "hunter2");
+
+    config.network.get_mut("one").unwrap().password_env = Some("MISSING".to_string());
+    let err = config.read_password_env(env).unwrap_err();
+    assert_eq!(format!("{}", err), "network one password_env MISSING");
+}
+
+#[test]
+fn test_channel_key() {
+    let config = BotConfig::parse(
+        r##"
+        [network.example]
+        server = "irc.example.com"
+        nickname = "annoirc"
+        channels = ["#Secret"]
+        channel_keys = { "#Secret" = "hunter2" }
+        "##,
+    )
+    .unwrap();
+    let netconf = &config.network["example"];
+    assert_eq!(netconf.channel_key("#secret"), Some("hunter2"));
+    assert_eq!(netconf.channel_key("#other"), None);
+}
+
+#[test]
+fn test_command_prefix() {
+    let config = BotConfig::parse(
+        r##"
+        [network.example]
+        server = "irc.example.com"
+        nickname = "annoirc"
+        channel."#Foo".prefix = "!"
+        "##,
+    )
+    .unwrap();
+    let netconf = &config.network["example"];
+    assert_eq!(netconf.command_prefix("#foo", &config.command), "!");
+    assert_eq!(netconf.command_prefix("#FOO", &config.command), "!");
+    assert_eq!(netconf.command_prefix("#bar", &config.command), ".");
+
+    assert!(BotConfig::parse(
+        "[network.example]\nserver = \"irc.example.com\"\nnickname = \"annoirc\"\nchannel.\"#foo\".prefix = \"\""
+    )
+    .is_err());
+}
+
+#[test]
+fn test_twitter_links() {
+    let config = BotConfig::parse(
+        r##"
+        [network.example]
+        server = "irc.example.com"
+        nickname = "annoirc"
+        channel."#native".rewrite_to_nitter = false
+        channel."#nitter".rewrite_to_nitter = true
+        "##,
+    )
+    .unwrap();
+    let netconf = &config.network["example"];
+    let mut twitter = config.twitter.clone();
+
+    // Nothing is rewritten until there's somewhere to rewrite to
+    assert_eq!(
+        netconf.twitter_links("#nitter", &twitter),
+        TwitterLinks::Original
+    );
+
+    twitter.nitter_host = Some("nitter.example".to_string());
+    assert_eq!(
+        netconf.twitter_links("#chan", &twitter),
+        TwitterLinks::Nitter("nitter.example")
+    );
+    assert_eq!(
+        netconf.twitter_links("#Native", &twitter),
+        TwitterLinks::Original
+    );
+
+    twitter.rewrite_to_nitter = false;
+    assert_eq!(
+        netconf.twitter_links("#chan", &twitter),
+        TwitterLinks::Original
+    );
+    assert_eq!(
+        netconf.twitter_links("#nitter", &twitter),
+        TwitterLinks::Nitter("nitter.example")
+    );
+
+    assert!(BotConfig::parse("[twitter]\nnitter_host = \"nitter.example\"").is_ok());
+    assert!(BotConfig::parse("[twitter]\nnitter_host = \"nitter.example/x\"").is_err());
+}
+
+#[test]
+fn test_locale_for() {
+    let config = BotConfig::parse(
+        r##"
+        [locale.default]
+        utc_offset = "+00:00"
+
+        [locale.de]
+        numbers = "de"
+        utc_offset = "+01:00"
+        language = "de"
+
+        [network.example]
+        server = "irc.example.com"
+        nickname = "annoirc"
+        locale = "de"
+        channel."#english".locale = "default"
+
+        [network.other]
+        server = "irc.example.org"
+        nickname = "annoirc"
+        "##,
+    )
+    .unwrap();
+    let example = config.network.get("example");
+    let other = config.network.get("other");
+
+    assert_eq!(config.locale_for(example, "#chan").numbers, Locale::de);
+    assert_eq!(config.locale_for(example, "#English").numbers, Locale::en);
+    assert_eq!(config.locale_for(other, "#chan").numbers, Locale::en);
+    assert_eq!(
+        config.locale_for(other, "#chan").utc_offset,
+        FixedOffset::east_opt(0)
+    );
+    assert_eq!(
+        BotConfig::default().locale_for(example, "#chan").utc_offset,
+        None
+    );
+
+    assert_eq!(
+        config.url_options(example, "#chan"),
+        UrlOptions {
+            policy: UrlPolicy::GlobalOnly,
+            language: Some("de".to_string())
+        }
+    );
+    assert_eq!(config.url_options(None, "").language, None);
+
+    assert!(BotConfig::parse("[locale.x]\nnumbers = \"xx\"").is_err());
+    assert!(BotConfig::parse("[locale.x]\nutc_offset = \"CET\"").is_err());
+    assert!(BotConfig::parse("[locale.x]\nlanguage = \"de,en\"").is_err());
+    let network = "[network.x]\nserver = \"irc\"\nnickname = \"bot\"\n";
+    assert!(BotConfig::parse(&format!("{}locale = \"missing\"", network)).is_err());
+    assert!(BotConfig::parse(&format!("{}locale = \"default\"", network)).is_ok());
+}
+
+impl UrlConfig {
+    /// Which addresses lookups may reach, unless overridden per network
+    pub fn policy(&self) -> UrlPolicy {
+        UrlPolicy::from_routable_only(self.globally_routable_only)
+    }
+
+    pub fn allows_scheme(&self, scheme: &str) -> bool {
+        self.allowed_schemes
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(scheme))
+    }
+}
+
 impl Default for CommandConfig {
     fn default() -> Self {
         Self {
             max_concurrency: 8,
+            max_pending_per_connection: 32,
             max_runtime_secs: 10,
+            max_runtime_secs_per_kind: HashMap::new(),
             cache_time_secs: 1800,
+            error_cache_time_secs: None,
+            report_rate_limits: false,
+            max_lines_per_message: 8,
+            note_truncated_output: false,
             cache_entries: 256,
+            cache_max_bytes: None,
             prefix: ".".to_string(),
+            audit_log: None,
+            with_urls: CommandWithUrls::CommandOrUrls,
+            admin_only: vec![],
+            channels_per_kind: HashMap::new(),
+            restricted_notice: None,
         }
     }
 }
 
+impl CommandConfig {
+    /// Maximum runtime for a given kind of command, falling back to the global limit
+    pub fn max_runtime(&self, kind: &str) -> Duration {
+        Duration::from_secs(
+            *self
+                .max_runtime_secs_per_kind
+                .get(kind)
+                .unwrap_or(&self.max_runtime_secs) as u64,
+        )
+    }
+
+    /// Whether a given kind of command may run in a channel
+    pub fn allowed_in(&self, kind: &str, channel: &str) -> bool {
+        self.channels_per_kind
+            .get(kind)
+            .is_none_or(|channels| channels.iter().any(|c| c.eq_ignore_ascii_case(channel)))
+    }
+}
+
+#[test]
+fn test_command_allowed_in() {
+    let mut config = CommandConfig::default();
+    assert!(config.allowed_in("wolfram", "#chat"));
+
+    config
+        .channels_per_kind
+        .insert("wolfram".to_string(), vec!["#maths".to_string()]);
+    assert!(config.allowed_in("wolfram", "#maths"));
+    assert!(config.allowed_in("wolfram", "#Maths"));
+    assert!(!config.allowed_in("wolfram", "#chat"));
+    assert!(config.allowed_in("omdb", "#chat"));
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(deny_unknown_fields, default)]
 pub struct TemplateConfig {
     pub title: String,
     pub tweet: String,
+    #[serde(deserialize_with = "parse_time_format")]
+    pub time_format: TimeFormat,
+    /// Truncate hostnames shown in responses to this many bytes
+    pub max_host_bytes: u8,
+    /// Truncate nicks from messages to this many bytes, whether shown in
+    /// responses or logged
+    pub max_nick_bytes: u8,
+}
+
+/// How dates are rendered in responses
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeFormat {
+    /// A strftime pattern, e.g. `%F`
+    Absolute(String),
+    /// Relative to now, e.g. "3 days ago"
+    Relative,
+}
+
+fn parse_time_format<'de, D>(d: D) -> Result<TimeFormat, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    if s == "relative" {
+        return Ok(TimeFormat::Relative);
+    }
+    if StrftimeItems::new(&s).any(|item| item == Item::Error) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid time format: {:?}",
+            s
+        )));
+    }
+    Ok(TimeFormat::Absolute(s))
 }
 
 impl Default for TemplateConfig {
     fn default() -> Self {
         Self {
             title: "[{{ host }}] {{ title }}".to_string(),
-            tweet: "[Twitter] {{ user.name }}{% if user.verified %}✓{% endif %} (@{{ user.screen_name }}) {{ tweet.text }} | {% if tweet.favorite_count > 0 %}❤️{{ tweet.favorite_count }} {% endif %}{{ tweet.created_at | date(\"%F %H:%M\") }}".to_string()
+            tweet: "[Twitter] {{ user.name }}{% if user.verified %}✓{% endif %} (@{{ user.screen_name }}) {{ tweet.text }} | {% if tweet.favorite_count > 0 %}❤️{{ tweet.favorite_count }} {% endif %}{{ tweet.created_at | date(\"%F %H:%M\") }}".to_string(),
+            time_format: TimeFormat::Absolute("%F".to_string()),
+            max_host_bytes: 30,
+            max_nick_bytes: 50,
         }
     }
 }
 
 impl BotConfig {
-    async fn load(path: &Path) -> Result<BotConfig> {
-        const LIMIT: usize = 128 * 1024;
-        let mut config = String::new();
-        if tokio::fs::File::open(&path)
-            .await?
-            .take(LIMIT as u64)
-            .read_to_string(&mut config)
-            .await?
-            == LIMIT
+    /// Load and validate a configuration file, and any secrets file it names
+    pub async fn load(path: &Path) -> Result<BotConfig> {
+        let text = read_limited(path).await?;
+        let mut table: toml::Table = toml::from_str(&text)?;
+
+        if let Some(secrets) = table.get("secrets_file").and_then(|s| s.as_str()) {
+            let secrets = path.parent().unwrap_or_else(|| Path::new("")).join(secrets);
+            let overrides = read_limited(&secrets)
+                .await
+                .and_then(|text| Ok(toml::from_str::<toml::Table>(&text)?))
+                .with_context(|| format!("secrets file {}", secrets.display()))?;
+            overlay(&mut table, overrides);
+        }
+
+        Self::from_table(table, &text)
+    }
+
+    /// The locale for a channel: its own, its network's, or the default
+    pub fn locale_for(&self, netconf: Option<&NetworkConfig>, channel: &str) -> &LocaleConfig {
+        netconf
+            .and_then(|netconf| {
+                netconf
+                    .channel_config(channel)
+                    .and_then(|c| c.locale.as_ref())
+                    .or(netconf.locale.as_ref())
+            })
+            .and_then(|name| self.locale.get(name))
+            .or_else(|| self.locale.get("default"))
+            .unwrap_or(&DEFAULT_LOCALE)
+    }
+
+    /// How to look up URLs posted in a channel, or by API clients without one
+    pub fn url_options(&self, netconf: Option<&NetworkConfig>, channel: &str) -> UrlOptions {
+        UrlOptions {
+            policy: netconf.map_or_else(|| self.url.policy(), |n| n.url_policy(&self.url)),
+            language: self.locale_for(netconf, channel).language.clone(),
+        }
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs.unwrap_or(2) as u64)
+    }
+
+    #[cfg(test)]
+    pub fn parse(text: &str) -> Result<BotConfig> {
+        Self::from_table(toml::from_str(text)?, text)
+    }
+
+    fn from_table(mut table: toml::Table, text: &str) -> Result<BotConfig> {
+        inherit_groups(&mut table)?;
+        let mut config: BotConfig = match toml::Value::Table(table).try_into() {
+            Ok(config) => config,
+            // Values don't know where they came from, so find the error's line
+            // and column by deserializing the text as written
+            Err(e) => {
+                return Err(match toml::from_str::<BotConfig>(text) {
+                    Err(located) => located.into(),
+                    Ok(_) => anyhow::Error::new(e)
+                        .context("invalid setting from a group or the secrets file"),
+                })
+            }
+        };
+        for network in config.network.values_mut() {
+            network.irc.realname = network.irc.realname.as_deref().map(identity);
+            network.irc.version = Some(identity(
+                network.irc.version.as_deref().unwrap_or(DEFAULT_VERSION),
+            ));
+        }
+        config.read_password_env(|var| std::env::var(var))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Fill in server passwords from the variables networks name with
+    /// password_env, looked up with `env`
+    fn read_password_env<F>(&mut self, env: F) -> Result<()>
+    where
+        F: Fn(&str) -> Result<String, std::env::VarError>,
+    {
+        for (name, network) in self.network.iter_mut() {
+            if let Some(var) = &network.password_env {
+                network.irc.password = Some(
+                    env(var).with_context(|| format!("network {} password_env {}", name, var))?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.url.timeout_secs == 0 {
+            return Err(anyhow!("url.timeout_secs must be at least 1"));
+        }
+        match self.url.connect_timeout_secs {
+            Some(0) => return Err(anyhow!("url.connect_timeout_secs must be at least 1")),
+            Some(secs) if secs > self.url.timeout_secs => {
+                return Err(anyhow!(
+                    "url.connect_timeout_secs must not exceed url.timeout_secs"
+                ))
+            }
+            _ => (),
+        }
+        if self.url.title_selectors.is_empty() {
+            return Err(anyhow!("url.title_selectors must not be empty"));
+        }
+        if self.template.max_host_bytes < 8 || self.template.max_nick_bytes < 8 {
+            return Err(anyhow!(
+                "template.max_host_bytes and max_nick_bytes must be at least 8"
+            ));
+        }
+        if self.factoid.max_key_bytes == 0
+            || self.factoid.max_value_bytes == 0
+            || self.factoid.max_value_bytes as usize > LINE_BYTES
         {
-            return Err(anyhow!("excessively large configuration"));
+            return Err(anyhow!(
+                "factoid.max_key_bytes must be at least 1, and max_value_bytes between 1 and {}",
+                LINE_BYTES
+            ));
+        }
+        if self.url.min_url_share_percent > 100 {
+            return Err(anyhow!("url.min_url_share_percent must not exceed 100"));
+        }
+        if self.url.max_per_host == 0 {
+            return Err(anyhow!("url.max_per_host must be at least 1"));
+        }
+        if let Some(host) = &self.twitter.nitter_host {
+            if url::Host::parse(host).is_err() {
+                return Err(anyhow!("twitter.nitter_host {:?} isn't a valid host", host));
+            }
+        }
+        if self.command.max_lines_per_message == 0 {
+            return Err(anyhow!("command.max_lines_per_message must be at least 1"));
+        }
+        if self.command.prefix.is_empty() {
+            return Err(anyhow!("command.prefix must not be empty"));
+        }
+        if self.max_networks == Some(0) {
+            return Err(anyhow!("max_networks must be at least 1"));
+        }
+        for schedule in &self.schedule {
+            match self.network.get(&schedule.network) {
+                Some(network) if network.irc.channels.contains(&schedule.channel) => (),
+                Some(_) => {
+                    return Err(anyhow!(
+                        "scheduled message for {} channel {} is not in its channels",
+                        schedule.network,
+                        schedule.channel
+                    ))
+                }
+                None => {
+                    return Err(anyhow!(
+                        "scheduled message for unknown network {}",
+                        schedule.network
+                    ))
+                }
+            }
+        }
+        for (name, targets) in &self.broadcast {
+            for (network, channels) in targets {
+                let known = self
+                    .network
+                    .get(network)
+                    .ok_or_else(|| anyhow!("broadcast {} has unknown network {}", name, network))?;
+                if let Some(channel) = channels.iter().find(|c| !known.irc.channels.contains(c)) {
+                    return Err(anyhow!(
+                        "broadcast {} channel {} is not in network {}'s channels",
+                        name,
+                        channel,
+                        network
+                    ));
+                }
+            }
+        }
+        for (name, locale) in &self.locale {
+            if let Some(language) = &locale.language {
+                accept_language(language).with_context(|| format!("locale {}", name))?;
+            }
+        }
+        let known_locale = |locale: &Option<String>| {
+            locale
+                .as_ref()
+                .is_none_or(|locale| locale == "default" || self.locale.contains_key(locale))
+        };
+        for (name, network) in &self.network {
+            if !known_locale(&network.locale) {
+                return Err(anyhow!("network {} has unknown locale", name));
+            }
+            if let Some(channel) = network
+                .channel
+                .iter()
+                .find(|(_, chanconf)| !known_locale(&chanconf.locale))
+                .map(|(channel, _)| channel)
+            {
+                return Err(anyhow!(
+                    "network {} channel {} has unknown locale",
+                    name,
+                    channel
+                ));
+            }
+            if network.irc.server.as_deref().unwrap_or_default().is_empty() {
+                return Err(anyhow!("network {} has no server", name));
+            }
+            if network
+                .irc
+                .nickname
+                .as_deref()
+                .unwrap_or_default()
+                .is_empty()
+            {
+                return Err(anyhow!("network {} has no nickname", name));
+            }
+            if let Some(nickserv) = &network.nickserv {
+                if nickserv.password.is_empty() || nickserv.service.is_empty() {
+                    return Err(anyhow!(
+                        "network {} nickserv needs a password and service",
+                        name
+                    ));
+                }
+                if network.irc.nick_password.is_some() {
+                    return Err(anyhow!(
+                        "network {} sets both nickserv and nick_password",
+                        name
+                    ));
+                }
+            }
+            if let Some(channel) = &network.server_notices.channel {
+                if !network.irc.channels.contains(channel) {
+                    return Err(anyhow!(
+                        "network {} server_notices.channel {} is not in channels",
+                        name,
+                        channel
+                    ));
+                }
+            }
+            for (channel, chanconf) in &network.channel {
+                if let Some(bytes) = chanconf.max_preview_bytes {
+                    if bytes < 10 || bytes as usize > LINE_BYTES {
+                        return Err(anyhow!(
+                            "network {} channel {} max_preview_bytes must be between 10 and {}",
+                            name,
+                            channel,
+                            LINE_BYTES
+                        ));
+                    }
+                }
+                if chanconf.prefix.as_deref() == Some("") {
+                    return Err(anyhow!(
+                        "network {} channel {} prefix must not be empty",
+                        name,
+                        channel
+                    ));
+                }
+            }
+            if network.fingerprint.as_deref() == Some("") {
+                return Err(anyhow!("network {} fingerprint must not be empty", name));
+            }
+            if network.warmup_messages_per_minute == Some(0) {
+                return Err(anyhow!(
+                    "network {} warmup_messages_per_minute must be at least 1",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Largest configuration or secrets file we'll read, unless overridden by the
+/// `ANNOIRC_MAX_CONFIG_BYTES` environment variable
+const DEFAULT_MAX_CONFIG_BYTES: u64 = 128 * 1024;
+
+fn max_config_bytes() -> Result<u64> {
+    match std::env::var("ANNOIRC_MAX_CONFIG_BYTES") {
+        Ok(limit) => limit
+            .parse()
+            .with_context(|| format!("invalid ANNOIRC_MAX_CONFIG_BYTES {:?}", limit)),
+        Err(_) => Ok(DEFAULT_MAX_CONFIG_BYTES),
+    }
+}
+
+async fn read_limited(path: &Path) -> Result<String> {
+    read_limited_to(path, max_config_bytes()?).await
+}
+
+async fn read_limited_to(path: &Path, limit: u64) -> Result<String> {
+    let mut file = tokio::fs::File::open(&path).await?;
+    let mut data = Vec::new();
+    // Read one byte more than allowed, so a file exactly at the limit is fine
+    (&mut file)
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut data)
+        .await?;
+
+    if data.len() as u64 > limit {
+        let size = match file.metadata().await {
+            Ok(meta) => meta.len(),
+            Err(_) => data.len() as u64,
+        };
+        return Err(anyhow!(
+            "excessively large configuration: {} bytes, limit {}",
+            size,
+            limit
+        ));
+    }
+    Ok(String::from_utf8(data)?)
+}
+
+/// What we answer CTCP VERSION with unless configured otherwise
+const DEFAULT_VERSION: &str = "{name} {version}";
+
+/// Fill in our name and version in a realname or CTCP VERSION reply
+fn identity(template: &str) -> String {
+    template
+        .replace("{name}", env!("CARGO_PKG_NAME"))
+        .replace("{version}", env!("CARGO_PKG_VERSION"))
+}
+
+#[test]
+fn test_identity() {
+    let network = "[network.one]\nserver = \"irc.example\"\nnickname = \"bot\"\n";
+    let config = BotConfig::parse(network).unwrap();
+    let irc = &config.network["one"].irc;
+    assert_eq!(
+        irc.version(),
+        format!("annoirc {}", env!("CARGO_PKG_VERSION"))
+    );
+    assert_eq!(irc.real_name(), "bot");
+
+    let config = BotConfig::parse(&format!(
+        "{}realname = \"Annotating Bot v{{version}}\"\nversion = \"No\"\n",
+        network
+    ))
+    .unwrap();
+    let irc = &config.network["one"].irc;
+    assert_eq!(irc.version(), "No");
+    assert_eq!(
+        irc.real_name(),
+        format!("Annotating Bot v{}", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[tokio::test]
+async fn test_read_limited_boundary() {
+    let path = std::env::temp_dir().join(format!("annoirc-limit-{}.toml", std::process::id()));
+    let limit = DEFAULT_MAX_CONFIG_BYTES as usize;
+    let config = "[network.example]\nserver = \"irc.example.com\"\nnickname = \"annoirc\"\n";
+    let padded = |len: usize| format!("{}#{}\n", config, "-".repeat(len - config.len() - 2));
+
+    std::fs::write(&path, padded(limit)).unwrap();
+    let res = BotConfig::load(&path).await;
+
+    std::fs::write(&path, padded(limit + 1)).unwrap();
+    let over = BotConfig::load(&path).await;
+    let _ = std::fs::remove_file(&path);
+
+    assert!(res.is_ok(), "{:?}", res.err());
+    assert_eq!(
+        over.unwrap_err().to_string(),
+        format!(
+            "excessively large configuration: {} bytes, limit {}",
+            limit + 1,
+            limit
+        )
+    );
+}
+
+/// Merge settings over a configuration, replacing values but merging tables,
+/// so e.g. `[network.example] password = ...` leaves the network's other
+/// settings alone
+fn overlay(config: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (config.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => {
+                overlay(existing, value)
+            }
+            (_, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_secrets_overlay() {
+    let mut table: toml::Table = toml::from_str(
+        r##"
+        [omdb]
+        disambiguate = true
+        api_key = "placeholder"
+
+        [group.mesh]
+        server = "irc.example.com"
+
+        [network.example]
+        group = "mesh"
+        nickname = "annoirc"
+        channels = ["#chan"]
+        "##,
+    )
+    .unwrap();
+    let secrets: toml::Table = toml::from_str(
+        r#"
+        omdb.api_key = "omdb-secret"
+        youtube.api_key = "youtube-secret"
+        group.mesh.password = "mesh-secret"
+        "#,
+    )
+    .unwrap();
+    overlay(&mut table, secrets);
+
+    let config = BotConfig::from_table(table, "").unwrap();
+    assert_eq!(config.omdb.api_key.as_deref(), Some("omdb-secret"));
+    assert!(config.omdb.disambiguate);
+    assert_eq!(config.youtube.api_key.as_deref(), Some("youtube-secret"));
+    let network = &config.network["example"].irc;
+    assert_eq!(network.password.as_deref(), Some("mesh-secret"));
+    assert_eq!(network.server.as_deref(), Some("irc.example.com"));
+    assert_eq!(network.channels, vec!["#chan".to_string()]);
+}
+
+/// Fill in each network's settings from its group, with its own taking precedence
+fn inherit_groups(config: &mut toml::Table) -> Result<()> {
+    let groups = config
+        .get("group")
+        .and_then(|g| g.as_table())
+        .cloned()
+        .unwrap_or_default();
+    let networks = match config.get_mut("network").and_then(|n| n.as_table_mut()) {
+        Some(networks) => networks,
+        None => return Ok(()),
+    };
+
+    for (name, network) in networks.iter_mut() {
+        let network = match network.as_table_mut() {
+            Some(network) => network,
+            None => continue,
+        };
+        let group = match network.get("group").and_then(|g| g.as_str()) {
+            Some(group) => group,
+            None => continue,
+        };
+        let mut merged = groups
+            .get(group)
+            .and_then(|g| g.as_table())
+            .cloned()
+            .ok_or_else(|| anyhow!("network {} is in undefined group {}", name, group))?;
+        merged.extend(std::mem::take(network));
+        *network = merged;
+    }
+
+    Ok(())
+}
+
+async fn load_initial(
+    log: &Logger,
+    path: &Path,
+    retries: u32,
+    retry_delay: Duration,
+) -> Result<BotConfig> {
+    let mut attempt = 0;
+    loop {
+        match BotConfig::load(path).await {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(log, "load"; "status" => "retrying", "attempt" => attempt, "retries" => retries, "error" => format!("{:#}", e), "path" => %path.display());
+                tokio::time::sleep(retry_delay).await;
+            }
+            res => return res,
         }
-        Ok(toml::from_str(&config)?)
     }
 }
 
+#[tokio::test(start_paused = true)]
+async fn test_load_initial_retries() {
+    let log = Logger::root(slog::Discard, slog::o!());
+    let start = tokio::time::Instant::now();
+    let res = load_initial(
+        &log,
+        Path::new("does/not/exist.toml"),
+        2,
+        Duration::from_secs(5),
+    )
+    .await;
+    assert!(res.is_err());
+    assert!(start.elapsed() >= Duration::from_secs(10));
+}
+
 impl ConfigMonitor {
-    /// Begin monitoring the specified configuration file, if it exists
-    pub async fn watch<P: Into<PathBuf>>(log: Logger, path: P) -> Result<ConfigMonitor> {
+    /// Begin monitoring the specified configuration file, retrying the initial
+    /// load up to `retries` times in case it isn't available yet
+    pub async fn watch<P: Into<PathBuf>>(
+        log: Logger,
+        path: P,
+        retries: u32,
+        retry_delay: Duration,
+    ) -> Result<ConfigMonitor> {
         let path = path.into();
 
-        let config = BotConfig::load(&path).await.map_err(|e| {
-            crit!(log, "load"; "status" => "failed", "error" => %e, "path" => %path.display());
-            anyhow!("Failed loading initial configuration")
-        })?;
-        let (tx, rx) = watch::channel(Arc::new(config));
-
-        let tx = ConfigUpdater(Arc::new(Mutex::new(Some(tx))));
-        let rx = ConfigMonitor(rx);
+        let config = load_initial(&log, &path, retries, retry_delay)
+            .await
+            .map_err(|e| {
+                crit!(log, "load"; "status" => "failed", "error" => format!("{:#}", e), "path" => %path.display());
+                anyhow!("Failed loading initial configuration")
+            })?;
+        let (rx, tx) = Self::from_config(config);
 
         #[cfg(not(unix))]
         {
@@ -218,7 +1685,7 @@ impl ConfigMonitor {
                                     tx.update(c);
                                 }
                                 Err(e) => {
-                                    error!(log, "reload"; "status" => "ignored", "error" => %e, "path" => %path.display());
+                                    error!(log, "reload"; "status" => "ignored", "error" => format!("{:#}", e), "path" => %path.display());
                                 }
                             }
                         },
@@ -234,6 +1701,21 @@ impl ConfigMonitor {
         Ok(rx)
     }
 
+    /// Monitor an in-memory configuration, changed only through the returned
+    /// `ConfigUpdater` rather than by reloading a file
+    pub fn from_config(config: BotConfig) -> (ConfigMonitor, ConfigUpdater) {
+        let (tx, rx) = watch::channel(Arc::new(config));
+        (
+            ConfigMonitor(rx),
+            ConfigUpdater(Arc::new(Mutex::new(Some(tx)))),
+        )
+    }
+
+    /// A configuration which never changes
+    pub fn fixed(config: BotConfig) -> Self {
+        Self::from_config(config).0
+    }
+
     /// Retrieve a copy of the current configuration
     pub fn current(&self) -> Arc<BotConfig> {
         self.0.borrow().clone()
@@ -263,3 +1745,144 @@ impl ConfigUpdater {
         self.0.lock().unwrap().take().is_some()
     }
 }
+
+#[tokio::test]
+async fn test_config_updates() {
+    let (mut monitor, updater) = ConfigMonitor::from_config(BotConfig::default());
+    assert_eq!(monitor.current().max_networks, None);
+
+    let config = BotConfig {
+        max_networks: Some(2),
+        ..BotConfig::default()
+    };
+    assert!(updater.update(config));
+    assert_eq!(monitor.next().await.unwrap().max_networks, Some(2));
+    assert_eq!(monitor.current().max_networks, Some(2));
+
+    assert!(updater.close());
+    assert!(monitor.next().await.is_none());
+    assert!(!updater.update(BotConfig::default()));
+}
+
+#[test]
+fn test_example_config() {
+    let config = BotConfig::parse(include_str!("../example.toml")).unwrap();
+    let network = &config.network["example"];
+    assert_eq!(network.irc.nickname.as_deref(), Some("annobot"));
+    assert_eq!(network.ping_interval_secs, None);
+    assert!(!network.process_notices);
+    assert!(network.ignores_nick("OtherBot"));
+    assert_eq!(
+        config.template.time_format,
+        TimeFormat::Absolute("%F".to_string())
+    );
+}
+
+#[test]
+fn test_network_groups() {
+    let config = BotConfig::parse(
+        r##"
+        [group.mesh]
+        nickname = "annobot"
+        channels = ["#mesh"]
+        group_dedupe_secs = 60
+
+        [network.one]
+        group = "mesh"
+        server = "irc.one.example"
+
+        [network.two]
+        group = "mesh"
+        server = "irc.two.example"
+        channels = ["#mesh", "#two"]
+        "##,
+    )
+    .unwrap();
+
+    let one = &config.network["one"];
+    assert_eq!(one.irc.nickname.as_deref(), Some("annobot"));
+    assert_eq!(one.irc.channels, vec!["#mesh"]);
+    assert_eq!(one.group.as_deref(), Some("mesh"));
+    assert_eq!(one.group_dedupe_secs, 60);
+
+    let two = &config.network["two"];
+    assert_eq!(two.irc.server.as_deref(), Some("irc.two.example"));
+    assert_eq!(two.irc.channels, vec!["#mesh", "#two"]);
+
+    assert!(BotConfig::parse("[network.one]\ngroup = \"missing\"").is_err());
+}
+
+#[test]
+fn test_validate_networks() {
+    assert!(BotConfig::parse("[network.one]\nnickname = \"bot\"").is_err());
+    assert!(BotConfig::parse("[network.one]\nserver = \"irc.example\"").is_err());
+    assert!(
+        BotConfig::parse("[network.one]\nserver = \"irc.example\"\nnickname = \"bot\"").is_ok()
+    );
+    assert!(BotConfig::parse("[command]\nprefix = \"\"").is_err());
+}
+
+#[test]
+fn test_validate_broadcast() {
+    let network =
+        "[network.one]\nserver = \"irc.example\"\nnickname = \"bot\"\nchannels = [\"#a\"]\n";
+    let broadcast = |targets: &str| {
+        BotConfig::parse(&format!("{}[broadcast.maintenance]\n{}", network, targets))
+    };
+    assert!(broadcast("one = [\"#a\"]").is_ok());
+    assert!(broadcast("one = [\"#b\"]").is_err());
+    assert!(broadcast("two = [\"#a\"]").is_err());
+}
+
+#[test]
+fn test_fingerprint() {
+    let mut netconf = NetworkConfig::default();
+    assert!(!netconf.has_fingerprint("[example.com] Example\u{2063}"));
+
+    netconf.fingerprint = Some("\u{2063}".to_string());
+    assert!(netconf.has_fingerprint("[example.com] Example\u{2063}"));
+    assert!(!netconf.has_fingerprint("https://example.com/"));
+
+    assert!(BotConfig::parse(
+        "[network.one]\nserver = \"irc.example\"\nnickname = \"bot\"\nfingerprint = \"\""
+    )
+    .is_err());
+}
+
+#[test]
+fn test_preview_bytes() {
+    let network = "[network.one]\nserver = \"irc.example\"\nnickname = \"bot\"\n";
+    let config = BotConfig::parse(&format!(
+        "{}[network.one.channel.\"#Terse\"]\nmax_preview_bytes = 100",
+        network
+    ))
+    .unwrap();
+    let netconf = &config.network["one"];
+    assert_eq!(netconf.preview_bytes("#terse"), 100);
+    assert_eq!(netconf.preview_bytes("#other"), LINE_BYTES);
+
+    assert!(BotConfig::parse(&format!(
+        "{}[network.one.channel.\"#terse\"]\nmax_preview_bytes = 1000",
+        network
+    ))
+    .is_err());
+}
+
+#[test]
+fn test_parse_error_location() {
+    let err = BotConfig::parse("[url]\ntimeout_secs = \"soon\"").unwrap_err();
+    assert!(err.to_string().contains("line 2, column 16"), "{}", err);
+
+    let err =
+        BotConfig::parse("[group.g]\nping_interval_secs = \"often\"\n[network.one]\ngroup = \"g\"")
+            .unwrap_err();
+    assert!(err.to_string().contains("line 2, column 22"), "{}", err);
+}
+
+#[test]
+fn test_validate_timeouts() {
+    assert!(BotConfig::parse("[url]\nconnect_timeout_secs = 0").is_err());
+    assert!(BotConfig::parse("[url]\ntimeout_secs = 5\nconnect_timeout_secs = 8").is_err());
+    assert!(BotConfig::parse("[url]\ntimeout_secs = 20\nconnect_timeout_secs = 8").is_ok());
+    assert_eq!(BotConfig::parse("").unwrap().url.connect_timeout_secs, None);
+}
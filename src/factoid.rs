@@ -0,0 +1,279 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use slog::{error, info, Logger};
+
+use crate::{
+    config::{BotConfig, FactoidConfig},
+    irc_string::sanitize,
+    registry,
+};
+
+/// Factoids by network, channel and key.  Channels are lowercased, as IRC
+/// treats their names case-insensitively.
+type Store = BTreeMap<String, BTreeMap<String, BTreeMap<String, String>>>;
+
+/// Things channels have taught us with `.learn`, optionally kept on disk
+#[derive(Debug, Default)]
+pub struct Factoids {
+    path: Option<PathBuf>,
+    store: Mutex<Store>,
+}
+
+impl Factoids {
+    /// Load any saved factoids.  If the file can't be read we start empty and
+    /// never save, rather than overwrite what's there.
+    pub fn load(log: &Logger, config: &FactoidConfig) -> Self {
+        let path = match &config.path {
+            Some(path) => path,
+            None => return Self::default(),
+        };
+
+        match read(path) {
+            Ok(store) => {
+                let store = lowercase_channels(store);
+                info!(log, "factoids"; "status" => "loaded", "path" => %path.display(), "channels" => store.values().map(BTreeMap::len).sum::<usize>());
+                Self {
+                    path: Some(path.clone()),
+                    store: Mutex::new(store),
+                }
+            }
+            Err(e) => {
+                error!(log, "factoids"; "status" => "failed", "error" => %e, "path" => %path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn get(&self, network: &str, channel: &str, key: &str) -> Option<String> {
+        self.store
+            .lock()
+            .unwrap()
+            .get(network)
+            .and_then(|channels| channels.get(&channel.to_ascii_lowercase()))
+            .and_then(|factoids| factoids.get(key))
+            .cloned()
+    }
+
+    /// Remember or replace a factoid, returning a reason if we won't
+    pub fn learn(
+        &self,
+        network: &str,
+        channel: &str,
+        key: String,
+        value: String,
+        max_per_channel: u16,
+    ) -> Result<(), String> {
+        let lowercase = channel.to_ascii_lowercase();
+        let mut store = self.store.lock().unwrap();
+        let factoids = store
+            .entry(network.to_string())
+            .or_default()
+            .entry(lowercase.clone())
+            .or_default();
+
+        if !factoids.contains_key(&key) && factoids.len() >= max_per_channel as usize {
+            return Err(format!("{} already knows enough", channel));
+        }
+        let previous = factoids.insert(key.clone(), value);
+
+        // Don't keep what we couldn't save
+        if let Err(e) = self.save(&store) {
+            if let Some(factoids) = store
+                .get_mut(network)
+                .and_then(|channels| channels.get_mut(&lowercase))
+            {
+                match previous {
+                    Some(previous) => factoids.insert(key, previous),
+                    None => factoids.remove(&key),
+                };
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Forget a factoid, returning whether we knew it
+    pub fn forget(&self, network: &str, channel: &str, key: &str) -> Result<bool, String> {
+        let mut store = self.store.lock().unwrap();
+        let removed = store
+            .get_mut(network)
+            .and_then(|channels| channels.get_mut(&channel.to_ascii_lowercase()))
+            .and_then(|factoids| factoids.remove(key))
+            .is_some();
+
+        if removed {
+            self.save(&store)?;
+        }
+        Ok(removed)
+    }
+
+    /// Write the store out while the lock is held, so saves can't race.  The
+    /// file is small and changes rarely.
+    fn save(&self, store: &Store) -> Result<(), String> {
+        match &self.path {
+            Some(path) => write(path, store).map_err(|e| format!("couldn't save: {}", e)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Merge channels saved under differently cased names
+fn lowercase_channels(store: Store) -> Store {
+    store
+        .into_iter()
+        .map(|(network, channels)| {
+            let mut merged = BTreeMap::<String, BTreeMap<String, String>>::new();
+            for (channel, factoids) in channels {
+                merged
+                    .entry(channel.to_ascii_lowercase())
+                    .or_default()
+                    .extend(factoids);
+            }
+            (network, merged)
+        })
+        .collect()
+}
+
+fn read(path: &Path) -> Result<Store> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Store::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write(path: &Path, store: &Store) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(store)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Normalise a factoid key, which must be a single word that isn't also a
+/// command
+pub fn parse_key(key: &str, config: &BotConfig) -> Result<String, String> {
+    let key = sanitize(key, usize::MAX).to_lowercase();
+    if key.is_empty() || key.contains(' ') {
+        Err("keys must be a single word".to_string())
+    } else if key.len() > config.factoid.max_key_bytes as usize {
+        Err(format!(
+            "keys must be at most {} bytes",
+            config.factoid.max_key_bytes
+        ))
+    } else if registry::COMMANDS
+        .iter()
+        .any(|spec| spec.names.contains(&key.as_str()))
+    {
+        Err(format!("{} is a command", key))
+    } else {
+        Ok(key)
+    }
+}
+
+/// Split `.learn` arguments into a key and value.  Values may not start with
+/// the channel's command prefix, so recalling a factoid can never trigger a
+/// command.
+pub fn parse_learn(
+    args: &str,
+    prefix: &str,
+    config: &BotConfig,
+) -> Result<(String, String), String> {
+    let (key, value) = args
+        .split_once('=')
+        .ok_or_else(|| "usage: learn <key> = <value>".to_string())?;
+    let key = parse_key(key, config)?;
+    let value = sanitize(value, config.factoid.max_value_bytes as usize);
+
+    if value.is_empty() {
+        Err("usage: learn <key> = <value>".to_string())
+    } else if value.starts_with(prefix) {
+        Err("values can't be commands".to_string())
+    } else {
+        Ok((key, value))
+    }
+}
+
+#[test]
+fn test_parse_learn() {
+    let config = BotConfig::default();
+    assert_eq!(
+        parse_learn(" Rust =  a \x02language\x02 ", ".", &config),
+        Ok(("rust".to_string(), "a language".to_string()))
+    );
+    assert_eq!(
+        parse_learn("x = a = b", ".", &config),
+        Ok(("x".to_string(), "a = b".to_string()))
+    );
+    assert!(parse_learn("rust", ".", &config).is_err());
+    assert!(parse_learn("rust =", ".", &config).is_err());
+    assert!(parse_learn("two words = no", ".", &config).is_err());
+    assert!(parse_learn("lasturl = shadowed", ".", &config).is_err());
+    assert!(parse_learn("loop = .loop", ".", &config).is_err());
+    assert!(parse_learn(&format!("{} = long", "k".repeat(33)), ".", &config).is_err());
+    assert!(parse_learn("loop = !loop", "!", &config).is_err());
+}
+
+#[test]
+fn test_factoids() {
+    let factoids = Factoids::default();
+    let learn = |key: &str| factoids.learn("net", "#chan", key.to_string(), "v".to_string(), 2);
+
+    assert_eq!(learn("a"), Ok(()));
+    assert_eq!(learn("b"), Ok(()));
+    assert!(learn("c").is_err());
+    assert_eq!(learn("a"), Ok(()));
+    assert_eq!(factoids.get("net", "#chan", "a"), Some("v".to_string()));
+    assert_eq!(factoids.get("net", "#other", "a"), None);
+
+    assert_eq!(factoids.get("net", "#Chan", "a"), Some("v".to_string()));
+
+    assert_eq!(factoids.forget("net", "#CHAN", "a"), Ok(true));
+    assert_eq!(factoids.forget("net", "#chan", "a"), Ok(false));
+    assert_eq!(learn("c"), Ok(()));
+}
+
+#[test]
+fn test_factoids_unsaved() {
+    let factoids = Factoids {
+        path: Some(PathBuf::from("/nonexistent/annoirc/factoids.json")),
+        store: Mutex::default(),
+    };
+    let learn = |value: &str| factoids.learn("net", "#chan", "k".to_string(), value.to_string(), 2);
+    assert!(learn("v").unwrap_err().starts_with("couldn't save"));
+    assert_eq!(factoids.get("net", "#chan", "k"), None);
+
+    // A replaced value comes back
+    factoids
+        .store
+        .lock()
+        .unwrap()
+        .entry("net".to_string())
+        .or_default()
+        .entry("#chan".to_string())
+        .or_default()
+        .insert("k".to_string(), "old".to_string());
+    assert!(learn("new").is_err());
+    assert_eq!(factoids.get("net", "#chan", "k"), Some("old".to_string()));
+}
+
+#[test]
+fn test_lowercase_channels() {
+    let mut store = Store::default();
+    let channels = store.entry("net".to_string()).or_default();
+    channels.insert(
+        "#Chan".to_string(),
+        [("a".to_string(), "1".to_string())].into(),
+    );
+    channels.insert(
+        "#chan".to_string(),
+        [("b".to_string(), "2".to_string())].into(),
+    );
+    let store = lowercase_channels(store);
+    assert_eq!(store["net"].len(), 1);
+    assert_eq!(store["net"]["#chan"].len(), 2);
+}
@@ -1,27 +1,842 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
 use egg_mode_text::url_entities;
-use futures::{stream::FuturesUnordered, TryFutureExt};
-use governor::{Quota, RateLimiter};
-use irc::client::prelude::*;
+use futures::{stream::FuturesUnordered, FutureExt, TryFutureExt};
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use irc::{client::prelude::*, proto::CapSubCommand};
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use nonzero_ext::*;
 use num_format::{Locale, ToFormattedString};
-use slog::{error, info, o, warn, Logger};
+use regex::Regex;
+use slog::{debug, error, info, o, warn, Logger};
 use tokio::{task::JoinHandle, time::Instant};
 use tokio_stream::StreamExt;
 use url::Url;
 
 use crate::{
-    command::*, config::*, irc_string::*, omdb::Movie, wolfram::WolframPod, youtube::*,
+    audit::{self, AuditEntry},
+    caps::{cap_list, Capabilities, ECHO_MESSAGE},
+    command::*,
+    config::*,
+    factoid,
+    irc_string::*,
+    omdb::{self, Candidate, Movie},
+    registry::{self, Action, CommandSpec},
+    wolfram::{Uninterpreted, WolframPod},
+    youtube::*,
 };
 
-#[derive(Debug)]
-struct CommandResponse {
-    log: Logger,
+type HistoryEntry = (Url, IrcString);
+
+/// Recently announced URLs, keyed by channel
+#[derive(Debug, Clone, Default)]
+struct UrlHistory(Arc<Mutex<HashMap<String, VecDeque<HistoryEntry>>>>);
+
+impl UrlHistory {
+    fn record(&self, channel: &str, url: Url, title: IrcString, limit: usize) {
+        let mut history = self.0.lock().unwrap();
+        let entries = history.entry(channel.to_string()).or_default();
+        entries.retain(|(u, _)| *u != url);
+        entries.push_front((url, title));
+        entries.truncate(limit);
+    }
+
+    fn recent(&self, channel: &str, count: usize) -> Vec<HistoryEntry> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(channel)
+            .map(|entries| entries.iter().take(count).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[test]
+fn test_url_history() {
+    let history = UrlHistory::default();
+    let url = |s: &str| Url::parse(s).unwrap();
+    history.record("#a", url("https://one.example/"), "One".into(), 2);
+    history.record("#a", url("https://two.example/"), "Two".into(), 2);
+    history.record("#b", url("https://other.example/"), "Other".into(), 2);
+    // Repeats move to the front rather than taking another slot
+    history.record("#a", url("https://one.example/"), "One".into(), 2);
+    history.record("#a", url("https://three.example/"), "Three".into(), 2);
+
+    let recent = history.recent("#a", 5);
+    assert_eq!(
+        recent,
+        vec![
+            (url("https://three.example/"), "Three".into()),
+            (url("https://one.example/"), "One".into()),
+        ]
+    );
+    assert_eq!(history.recent("#a", 1).len(), 1);
+    assert_eq!(history.recent("#b", 5).len(), 1);
+    assert!(history.recent("#c", 5).is_empty());
+
+    assert_eq!(
+        format_history(&recent),
+        "[\x0303History\x0f] 1. \x0303https://three.example/\x0f \x0300\x02\x02Three\x0f | 2. \x0303https://one.example/\x0f \x0300\x02\x02One\x0f"
+    );
+}
+
+/// How long a list of OMDB search results remains selectable
+const OMDB_CHOICE_TIME: Duration = Duration::from_secs(300);
+
+type OmdbChoice = (Instant, Vec<IrcString>);
+
+/// Recent OMDB search results per channel, for selection with `.imdb <n>`
+#[derive(Debug, Clone, Default)]
+struct OmdbChoices(Arc<Mutex<HashMap<String, OmdbChoice>>>);
+
+impl OmdbChoices {
+    fn offer(&self, channel: &str, imdb_ids: Vec<IrcString>) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(channel.to_string(), (Instant::now(), imdb_ids));
+    }
+
+    /// Pick the nth (1-based) result of the most recent search in a channel
+    fn choose(&self, channel: &str, n: usize) -> Option<IrcString> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(channel)
+            .filter(|(at, _)| at.elapsed() < OMDB_CHOICE_TIME)
+            .and_then(|(_, ids)| ids.get(n.checked_sub(1)?).cloned())
+    }
+}
+
+#[test]
+fn test_omdb_choices() {
+    let choices = OmdbChoices::default();
+    assert_eq!(choices.choose("#chan", 1), None);
+    choices.offer("#chan", vec!["tt1".into(), "tt2".into()]);
+    assert_eq!(choices.choose("#chan", 0), None);
+    assert_eq!(choices.choose("#chan", 2), Some("tt2".into()));
+    assert_eq!(choices.choose("#chan", 3), None);
+    assert_eq!(choices.choose("#other", 1), None);
+}
+
+/// Paces our messages for a while after registration, when joining channels
+/// and catching up on greetings is most likely to trip flood protection
+#[derive(Debug, Clone, Default)]
+struct Warmup {
+    pacer: Option<Arc<(Duration, DefaultDirectRateLimiter)>>,
+    started: Arc<Mutex<Option<Instant>>>,
+    exempt: Arc<AtomicBool>,
+}
+
+impl Warmup {
+    fn new(config: &NetworkConfig) -> Self {
+        let per_minute = NonZeroU32::new(config.warmup_messages_per_minute.unwrap_or(6));
+        match (config.warmup_secs, per_minute) {
+            (0, _) | (_, None) => Self::default(),
+            (secs, Some(per_minute)) => Self {
+                pacer: Some(Arc::new((
+                    Duration::from_secs(secs as u64),
+                    RateLimiter::direct(Quota::per_minute(per_minute)),
+                ))),
+                started: Default::default(),
+                exempt: Default::default(),
+            },
+        }
+    }
+
+    fn start(&self) {
+        *self.started.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn active(&self) -> bool {
+        match (&self.pacer, *self.started.lock().unwrap()) {
+            (Some(pacer), Some(started)) => started.elapsed() < pacer.0,
+            _ => false,
+        }
+    }
+
+    fn exempt(&self) -> bool {
+        self.exempt.load(Ordering::Relaxed)
+    }
+
+    fn set_exempt(&self, exempt: bool) {
+        self.exempt.store(exempt, Ordering::Relaxed);
+    }
+
+    /// Wait until we may send another message
+    async fn pace(self) {
+        if self.exempt() {
+            return;
+        }
+        if let (true, Some(pacer)) = (self.active(), &self.pacer) {
+            pacer.1.until_ready().await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_warmup() {
+    let config = NetworkConfig {
+        warmup_secs: 60,
+        warmup_messages_per_minute: Some(1),
+        ..Default::default()
+    };
+    let warmup = Warmup::new(&config);
+    assert!(!warmup.active());
+    warmup.start();
+    assert!(warmup.active());
+    warmup.clone().pace().await;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(10), warmup.clone().pace())
+            .await
+            .is_err()
+    );
+
+    warmup.set_exempt(true);
+    warmup.clone().pace().await;
+
+    assert!(Warmup::new(&NetworkConfig::default()).pacer.is_none());
+}
+
+/// Our own user modes, as far as the server has told us
+#[derive(Debug, Default)]
+struct UserModes(BTreeSet<char>);
+
+impl UserModes {
+    /// Apply a mode string such as "+iw-x"
+    fn apply(&mut self, changes: &str) {
+        let mut adding = true;
+        for c in changes.chars() {
+            match c {
+                '+' => adding = true,
+                '-' => adding = false,
+                c if adding => {
+                    self.0.insert(c);
+                }
+                c => {
+                    self.0.remove(&c);
+                }
+            }
+        }
+    }
+
+    fn any(&self, modes: &str) -> bool {
+        modes.chars().any(|c| self.0.contains(&c))
+    }
+}
+
+#[test]
+fn test_user_modes() {
+    let mut modes = UserModes::default();
+    assert!(!modes.any("o"));
+    modes.apply("+iwo");
+    assert!(modes.any("oO"));
+    modes.apply("-o+x");
+    assert!(!modes.any("oO"));
+    assert!(modes.any("x"));
+    assert!(!modes.any(""));
+}
+
+/// Limits on how much we say in response to each channel, with admins'
+/// diagnostic commands counted separately so they work in a busy channel,
+/// but can't be spammed either.  Channels have a limiter each, rather than
+/// sharing a keyed one, so an admin can reset one with `.unlimit`.
+struct CommandLimiter {
+    channels: HashMap<String, DefaultDirectRateLimiter>,
+    admins: DefaultDirectRateLimiter,
+    ctcp: DefaultDirectRateLimiter,
+}
+
+impl Default for CommandLimiter {
+    fn default() -> Self {
+        Self {
+            channels: HashMap::new(),
+            admins: RateLimiter::direct(Quota::per_minute(nonzero!(30u32))),
+            ctcp: RateLimiter::direct(Quota::per_minute(nonzero!(5u32))),
+        }
+    }
+}
+
+impl CommandLimiter {
+    /// Whether we may respond in a channel
+    fn check(&mut self, channel: &str) -> bool {
+        self.channels
+            .entry(channel.to_ascii_lowercase())
+            // Max of 10 per minute per channel
+            .or_insert_with(|| RateLimiter::direct(Quota::per_minute(nonzero!(10u32))))
+            .check()
+            .is_ok()
+    }
+
+    /// Whether we may run a command in a channel for its sender
+    fn check_command(&mut self, channel: &str, spec: &CommandSpec, admin: bool) -> bool {
+        if admin && spec.diagnostic {
+            self.admins.check().is_ok()
+        } else {
+            self.check(channel)
+        }
+    }
+
+    /// Whether we may answer a CTCP query, from anyone
+    fn check_ctcp(&self) -> bool {
+        self.ctcp.check().is_ok()
+    }
+
+    /// Restore a channel's full allowance, returning whether it had used any
+    fn reset(&mut self, channel: &str) -> bool {
+        self.channels
+            .remove(&channel.to_ascii_lowercase())
+            .is_some()
+    }
+}
+
+#[test]
+fn test_command_limiter() {
+    let config = BotConfig::default();
+    let why = registry::lookup("why", &config).unwrap();
+    let stats = registry::lookup("stats", &config).unwrap();
+    let title = registry::lookup("title", &config).unwrap();
+    let allowed = |spec, admin, n| {
+        let mut limiter = CommandLimiter::default();
+        (0..n)
+            .filter(|_| limiter.check_command("#chan", spec, admin))
+            .count()
+    };
+
+    assert_eq!(allowed(stats, false, 20), 10);
+    assert_eq!(allowed(why, true, 20), 20);
+    assert_eq!(allowed(stats, true, 40), 30);
+    assert_eq!(allowed(title, true, 20), 10);
+
+    // Exempt commands don't use up the channel's allowance
+    let mut limiter = CommandLimiter::default();
+    assert!((0..20).all(|_| limiter.check_command("#chan", why, true)));
+    assert!(limiter.check_command("#chan", title, false));
+}
+
+#[test]
+fn test_command_limiter_reset() {
+    let mut limiter = CommandLimiter::default();
+    assert!(!limiter.reset("#chan"));
+    assert_eq!((0..20).filter(|_| limiter.check("#chan")).count(), 10);
+    assert!(!limiter.check("#chan"));
+    assert!(limiter.check("#other"));
+
+    assert!(limiter.reset("#chan"));
+    assert_eq!((0..20).filter(|_| limiter.check("#chan")).count(), 10);
+    assert!(limiter.check("#other"));
+
+    // However the channel's name is written
+    assert!(!limiter.check("#Chan"));
+    assert!(limiter.reset("#CHAN"));
+    assert!(limiter.check("#chan"));
+}
+
+/// Sends our messages, marked with any configured fingerprint
+#[derive(Debug, Clone)]
+pub struct Output {
+    sender: Sender,
+    fingerprint: Option<String>,
+}
+
+impl Output {
+    pub fn privmsg<S: ToString>(&self, target: &str, text: S) -> Result<()> {
+        Ok(self.sender.send_privmsg(target, self.mark(text))?)
+    }
+
+    pub fn notice<S: ToString>(&self, target: &str, text: S) -> Result<()> {
+        Ok(self.sender.send_notice(target, self.mark(text))?)
+    }
+
+    fn mark<S: ToString>(&self, text: S) -> String {
+        let text = single_line(&text.to_string()).into_owned();
+        match &self.fingerprint {
+            Some(fingerprint) => text + fingerprint,
+            None => text,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_output_mark() {
+    let client = Client::from_config(Config {
+        nickname: Some("annoirc".to_string()),
+        use_mock_connection: true,
+        ..Config::default()
+    })
+    .await
+    .unwrap();
+    let output = Output {
+        sender: client.sender(),
+        fingerprint: Some("\u{200b}".to_string()),
+    };
+
+    let nick = "evil\r\nQUIT :bye\x0304";
+    assert_eq!(
+        output.mark(format!("[\x0303Hello\x0f] {}", sanitize(nick, 30))),
+        "[\x0303Hello\x0f] evil QUIT :bye\u{200b}"
+    );
+    assert_eq!(
+        output.mark(format!("[\x0303Hello\x0f] {}", nick)),
+        "[\x0303Hello\x0f] evil  QUIT :bye\x0304\u{200b}"
+    );
+}
+
+/// Where the responses to one message go, and how many lines they may still
+/// send between them
+#[derive(Debug, Clone)]
+struct Reply {
     target: String,
-    info: Arc<Result<Info>>,
+    output: Output,
+    remaining: Arc<AtomicUsize>,
+    noted: Arc<AtomicBool>,
+    note_truncated: bool,
+}
+
+/// What becomes of the next line of a reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Allowance {
+    Send,
+    /// Over the limit, but the first line over it, so say we've stopped
+    Note,
+    Drop,
+}
+
+impl Reply {
+    fn new(target: &str, output: &Output, config: &CommandConfig) -> Self {
+        Self {
+            target: target.to_string(),
+            output: output.clone(),
+            remaining: Arc::new(AtomicUsize::new(config.max_lines_per_message as usize)),
+            noted: Arc::new(AtomicBool::new(false)),
+            note_truncated: config.note_truncated_output,
+        }
+    }
+
+    fn allow(&self) -> Allowance {
+        if self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            Allowance::Send
+        } else if self.note_truncated && !self.noted.swap(true, Ordering::Relaxed) {
+            Allowance::Note
+        } else {
+            Allowance::Drop
+        }
+    }
+
+    fn privmsg<S: ToString>(&self, text: S) -> Result<()> {
+        match self.allow() {
+            Allowance::Send => self.output.privmsg(&self.target, text),
+            Allowance::Note => self
+                .output
+                .privmsg(&self.target, "(further output omitted)"),
+            Allowance::Drop => Ok(()),
+        }
+    }
+
+    fn notice<S: ToString>(&self, text: S) -> Result<()> {
+        match self.allow() {
+            Allowance::Send => self.output.notice(&self.target, text),
+            Allowance::Note | Allowance::Drop => Ok(()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_reply_limits_lines() {
+    let client = Client::from_config(Config {
+        nickname: Some("annoirc".to_string()),
+        use_mock_connection: true,
+        ..Config::default()
+    })
+    .await
+    .unwrap();
+    let output = Output {
+        sender: client.sender(),
+        fingerprint: None,
+    };
+    let mut config = CommandConfig {
+        max_lines_per_message: 3,
+        ..CommandConfig::default()
+    };
+
+    // Clones stand in for the several commands and URLs one message may set off
+    let reply = Reply::new("#chan", &output, &config);
+    let responses = [reply.clone(), reply.clone(), reply];
+    let allowed = responses
+        .iter()
+        .cycle()
+        .take(6)
+        .map(Reply::allow)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        allowed,
+        [
+            Allowance::Send,
+            Allowance::Send,
+            Allowance::Send,
+            Allowance::Drop,
+            Allowance::Drop,
+            Allowance::Drop
+        ]
+    );
+
+    config.note_truncated_output = true;
+    let reply = Reply::new("#chan", &output, &config);
+    let allowed = (0..5).map(|_| reply.clone().allow()).collect::<Vec<_>>();
+    assert_eq!(
+        allowed,
+        [
+            Allowance::Send,
+            Allowance::Send,
+            Allowance::Send,
+            Allowance::Note,
+            Allowance::Drop
+        ]
+    );
+}
+
+/// State shared between a connection and its in-flight commands
+#[derive(Debug, Clone, Default)]
+struct ConnectionState {
+    history: UrlHistory,
+    omdb_choices: OmdbChoices,
+    warmup: Warmup,
+}
+
+/// URLs recently posted by each user in each channel
+#[derive(Debug, Default)]
+struct UserDedupe(HashMap<(String, String, Url), Instant>);
+
+impl UserDedupe {
+    fn key(channel: &str, nick: &str, url: &Url) -> (String, String, Url) {
+        (
+            channel.to_ascii_lowercase(),
+            nick.to_ascii_lowercase(),
+            normalize_url(url),
+        )
+    }
+
+    /// Whether a user has posted a URL within the given window
+    fn seen(&mut self, channel: &str, nick: &str, url: &Url, window: Duration) -> bool {
+        let now = Instant::now();
+        self.0.retain(|_, at| now.duration_since(*at) < window);
+        self.0.contains_key(&Self::key(channel, nick, url))
+    }
+
+    /// Note a user's URL once we've acted on it
+    fn record(&mut self, channel: &str, nick: &str, url: &Url) {
+        self.0.insert(Self::key(channel, nick, url), Instant::now());
+    }
+}
+
+#[test]
+fn test_user_dedupe() {
+    let url = Url::parse("https://example.com/").unwrap();
+    let window = Duration::from_secs(60);
+    let mut dedupe = UserDedupe::default();
+    assert!(!dedupe.seen("#chan", "alice", &url, window));
+    // Only URLs we went on to look up count
+    assert!(!dedupe.seen("#chan", "alice", &url, window));
+    dedupe.record("#chan", "alice", &url);
+    assert!(dedupe.seen("#chan", "Alice", &url, window));
+    assert!(dedupe.seen("#Chan", "alice", &url, window));
+    assert!(!dedupe.seen("#chan", "bob", &url, window));
+    assert!(!dedupe.seen("#other", "alice", &url, window));
+    assert!(!dedupe.seen("#chan", "alice", &url, Duration::ZERO));
+}
+
+/// URLs recently previewed by any member of each network group, shared
+/// between connections so relayed messages aren't previewed twice
+#[derive(Debug, Clone, Default)]
+pub struct GroupDedupe(Arc<Mutex<HashMap<GroupUrl, Instant>>>);
+
+/// A URL previewed in a channel by a member of a network group
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GroupUrl {
+    group: String,
+    channel: String,
+    url: Url,
+}
+
+impl GroupUrl {
+    fn new(group: &str, channel: &str, url: &Url) -> Self {
+        Self {
+            group: group.to_string(),
+            channel: channel.to_ascii_lowercase(),
+            url: normalize_url(url),
+        }
+    }
+}
+
+impl GroupDedupe {
+    /// Whether any member of the group previewed a URL within the given window
+    fn seen(&self, group: &str, channel: &str, url: &Url, window: Duration) -> bool {
+        let now = Instant::now();
+        let mut previewed = self.0.lock().unwrap();
+        previewed.retain(|_, at| now.duration_since(*at) < window);
+        previewed.contains_key(&GroupUrl::new(group, channel, url))
+    }
+
+    /// Note a URL once a member has looked it up
+    fn record(&self, group: &str, channel: &str, url: &Url) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(GroupUrl::new(group, channel, url), Instant::now());
+    }
+}
+
+#[test]
+fn test_group_dedupe() {
+    let url = Url::parse("https://example.com/").unwrap();
+    let window = Duration::from_secs(60);
+    let dedupe = GroupDedupe::default();
+    assert!(!dedupe.seen("mesh", "#mesh", &url, window));
+    assert!(!dedupe.seen("mesh", "#mesh", &url, window));
+    dedupe.record("mesh", "#Mesh", &url);
+    assert!(dedupe.seen("mesh", "#mesh", &url, window));
+    assert!(!dedupe.seen("other", "#mesh", &url, window));
+    assert!(!dedupe.seen("mesh", "#elsewhere", &url, window));
+    assert!(!dedupe.seen("mesh", "#mesh", &url, Duration::ZERO));
+}
+
+/// What a connection does with a new configuration
+#[derive(Debug, PartialEq, Eq)]
+enum Reload {
+    /// Carry on, using it from the next message
+    Apply,
+    /// Our network's settings changed, so reconnect with them
+    Reconnect,
+    /// Our network was removed
+    Deconfigured,
+}
+
+fn reload_action(current: &NetworkConfig, config: &BotConfig, name: &str) -> Reload {
+    match config.network.get(name) {
+        None => Reload::Deconfigured,
+        Some(netconf) if netconf != current => Reload::Reconnect,
+        Some(_) => Reload::Apply,
+    }
+}
+
+#[test]
+fn test_reload_action() {
+    let mut config: BotConfig = toml::from_str(include_str!("../example.toml")).unwrap();
+    let netconf = config.network["example"].clone();
+
+    config.url.include_description = false;
+    config.url.max_per_message = 1;
+    config.template.time_format = TimeFormat::Relative;
+    config.command.prefix = "!".to_string();
+    assert_eq!(reload_action(&netconf, &config, "example"), Reload::Apply);
+
+    // Settings used per message take effect without reconnecting
+    let urls = extract_urls(
+        "https://example.com/ https://example.org/",
+        &config.url,
+        TwitterLinks::Original,
+    );
+    assert_eq!(urls.len(), 1);
+    let info = UrlInfo {
+        url: urls[0].clone(),
+        title: "A".into(),
+        desc: Some("Described".into()),
+    };
+    assert_eq!(format_url_info(&info, &config, LINE_BYTES).len(), 1);
+
+    config.network.get_mut("example").unwrap().irc.nickname = Some("other".to_string());
+    assert_eq!(
+        reload_action(&netconf, &config, "example"),
+        Reload::Reconnect
+    );
+
+    config.network.clear();
+    assert_eq!(
+        reload_action(&netconf, &config, "example"),
+        Reload::Deconfigured
+    );
+}
+
+/// Registered connections by network name, for sending outside of them
+#[derive(Debug, Clone, Default)]
+pub struct Connections(Arc<Mutex<HashMap<String, (Output, Warmup)>>>);
+
+impl Connections {
+    pub fn get(&self, network: &str) -> Option<Output> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(network)
+            .map(|(output, _)| output.clone())
+    }
+
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// Register a connection until the returned guard is dropped
+    fn register(&self, network: &str, output: Output, warmup: Warmup) -> Registration {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(network.to_string(), (output, warmup));
+        Registration(self.clone(), network.to_string())
+    }
+
+    /// Send a message to channels on each network in the background, paced as
+    /// each connection's own messages are, returning networks not connected
+    fn broadcast(
+        &self,
+        log: &Logger,
+        targets: &HashMap<String, Vec<String>>,
+        text: &str,
+    ) -> Vec<String> {
+        let mut missing = vec![];
+        for (network, channels) in targets.iter().sorted() {
+            let (output, warmup) = match self.0.lock().unwrap().get(network) {
+                Some(conn) => conn.clone(),
+                None => {
+                    missing.push(network.clone());
+                    continue;
+                }
+            };
+            let (log, network, channels, text) = (
+                log.clone(),
+                network.clone(),
+                channels.clone(),
+                text.to_string(),
+            );
+            tokio::spawn(async move {
+                for channel in channels {
+                    warmup.clone().pace().await;
+                    if let Err(e) = output.privmsg(&channel, &text) {
+                        warn!(log, "broadcast"; "network" => &network, "channel" => &channel, "error" => %e);
+                    }
+                }
+            });
+        }
+        missing
+    }
+}
+
+struct Registration(Connections, String);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        (self.0).0.lock().unwrap().remove(&self.1);
+    }
+}
+
+/// Register with a mock server which insists on a password before NICK,
+/// returning whether it would have let us in
+#[cfg(test)]
+async fn mock_registration(password: Option<&str>) -> bool {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut text = format!(
+        "[network.private]\nserver = \"127.0.0.1\"\nport = {}\nuse_tls = false\nnickname = \"bot\"\n",
+        listener.local_addr().unwrap().port()
+    );
+    if let Some(password) = password {
+        text += &format!("password = \"{}\"\n", password);
+    }
+    let config = BotConfig::parse(&text).unwrap();
+
+    let mut client = Client::from_config(config.network["private"].irc.clone())
+        .await
+        .unwrap();
+    client.identify().unwrap();
+    let mut stream = client.stream().unwrap();
+    tokio::spawn(async move { while stream.next().await.is_some() {} });
+
+    let (conn, _) = listener.accept().await.unwrap();
+    let mut lines = BufReader::new(conn).lines();
+    // Other lines, e.g. CAP or USER, may come between PASS and NICK
+    let mut authenticated = false;
+    while let Some(line) = lines.next_line().await.unwrap() {
+        if line.starts_with("NICK ") {
+            return authenticated;
+        }
+        authenticated |= line == "PASS hunter2";
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_server_password() {
+    assert!(mock_registration(Some("hunter2")).await);
+    assert!(!mock_registration(None).await);
+}
+
+/// How many speakers to remember per channel for greetings
+const MAX_SPEAKERS: usize = 4096;
+
+/// Who we've heard speak in each channel since we started, to greet people
+/// the first time they do
+#[derive(Default)]
+struct FirstSeen(HashMap<String, lru_time_cache::LruCache<String, ()>>);
+
+impl FirstSeen {
+    /// Record a nick speaking, returning whether we've never heard them before
+    fn spoke(&mut self, channel: &str, nick: &str) -> bool {
+        self.0
+            .entry(channel.to_lowercase())
+            .or_insert_with(|| lru_time_cache::LruCache::with_capacity(MAX_SPEAKERS))
+            .insert(nick.to_lowercase(), ())
+            .is_none()
+    }
+}
+
+impl std::fmt::Debug for FirstSeen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("FirstSeen")
+            .field(&format!("{} channels", self.0.len()))
+            .finish()
+    }
+}
+
+#[test]
+fn test_first_seen() {
+    let mut first_seen = FirstSeen::default();
+    assert!(first_seen.spoke("#chan", "alice"));
+    assert!(!first_seen.spoke("#Chan", "Alice"));
+    assert!(first_seen.spoke("#other", "alice"));
+    assert!(first_seen.spoke("#chan", "bob"));
+
+    // The oldest speakers are forgotten once a channel's store is full
+    for i in 0..MAX_SPEAKERS {
+        first_seen.spoke("#busy", &format!("nick{}", i));
+    }
+    assert!(!first_seen.spoke("#busy", "nick1"));
+    assert!(first_seen.spoke("#busy", "newcomer"));
+    assert!(first_seen.spoke("#busy", "nick0"));
+
+    assert_eq!(
+        format_greeting("Welcome to {channel}, {nick}!", "#chan", "alice"),
+        "Welcome to #chan, alice!"
+    );
+}
+
+fn format_greeting(template: &str, channel: &str, nick: &str) -> String {
+    template
+        .replace("{nick}", &sanitize(nick, usize::MAX))
+        .replace("{channel}", &sanitize(channel, usize::MAX))
 }
 
 #[derive(Debug)]
@@ -30,7 +845,10 @@ pub struct IrcTask {
     log: Logger,
     handler: CommandHandler,
     config: ConfigMonitor,
+    group_dedupe: GroupDedupe,
+    connections: Connections,
     throttle: Backoff,
+    first_seen: FirstSeen,
 }
 
 #[derive(Debug)]
@@ -54,10 +872,7 @@ impl Default for Backoff {
 impl Backoff {
     fn next(&mut self) -> Option<Duration> {
         let now = Instant::now();
-        let last = match self.last_attempt.replace(now) {
-            None => return None,
-            Some(attempt) => attempt,
-        };
+        let last = self.last_attempt.replace(now)?;
 
         let duration = now - last;
         let next_delay = if duration > self.max * 2 {
@@ -80,6 +895,8 @@ impl IrcTask {
         log: Logger,
         handler: CommandHandler,
         config: ConfigMonitor,
+        group_dedupe: GroupDedupe,
+        connections: Connections,
         name: String,
     ) -> JoinHandle<String> {
         let log = log.new(o!("network" => name.clone()));
@@ -87,8 +904,11 @@ impl IrcTask {
             log,
             handler,
             config,
+            group_dedupe,
+            connections,
             name,
             throttle: Backoff::default(),
+            first_seen: FirstSeen::default(),
         };
 
         tokio::spawn(async move {
@@ -143,41 +963,111 @@ impl IrcTask {
 
         let netconf = netconf.unwrap().clone();
 
-        warn!(self.log, "connect"; "server" => &netconf.server, "port" => &netconf.port);
+        warn!(self.log, "connect"; "server" => &netconf.irc.server, "port" => &netconf.irc.port);
 
         let mut shutdown = false;
+        // Once shutting down, we let pending output finish before QUIT, and
+        // wait for the server to close the connection, until this deadline
+        let mut deadline: Option<Instant> = None;
+        let mut quit_sent = false;
+        // Lets us be found by name while registered, e.g. for scheduled messages
+        let mut _registration = None;
 
-        let mut client = Client::from_config(netconf.clone()).await?;
+        // Identifying to services ourselves, we join channels once they
+        // recognise us rather than as soon as we're registered
+        let mut irc_config = netconf.irc.clone();
+        if netconf.nickserv.is_some() {
+            irc_config.channels.clear();
+        }
+        let mut client = Client::from_config(irc_config).await?;
         client.identify()?;
+        if !netconf.irc.password().is_empty() {
+            info!(self.log, "identify"; "pass" => "sent");
+        }
 
         let mut stream = client.stream()?;
         let mut pending = FuturesUnordered::new();
-        let quota = Quota::per_minute(nonzero!(10u32)); // Max of 10 per minute per channel
-        let limiter = RateLimiter::keyed(quota);
+        let mut limiter = CommandLimiter::default();
+        let state = ConnectionState {
+            warmup: Warmup::new(&netconf),
+            ..Default::default()
+        };
+        let mut user_dedupe = UserDedupe::default();
+        let mut user_modes = UserModes::default();
+        let mut registered = false;
+        let mut pinger = tokio::time::interval(Duration::from_secs(
+            netconf.ping_interval_secs.unwrap_or(60).max(1) as u64,
+        ));
+        let mut ping_sent: Option<(String, Instant)> = None;
+        // When we'll give up waiting for services to confirm we're identified
+        let mut identifying: Option<Instant> = None;
+        let mut reclaim: Option<Reclaim> = None;
+        let wanted = netconf.irc.nickname()?.to_string();
+        let mut caps = Capabilities::new(&netconf.capabilities);
+        // The irc crate only tracks nick changes it makes itself, not those forced on us
+        let mut nickname = client.current_nickname().to_string();
+        let mut delivered = 0u64;
+        let output = Output {
+            sender: client.sender(),
+            fingerprint: netconf.fingerprint.clone(),
+        };
 
         loop {
             tokio::select! {
                 newconf = self.config.next(), if !shutdown => {
-                    // Might be nice to have a timeout set up for dropping the connection.
                     if let Some(newconf) = newconf {
                         config = newconf;
-                        if let Some(new_netconf) = config.network.get(&self.name) {
-                            if *new_netconf != netconf {
+                        match reload_action(&netconf, &config, &self.name) {
+                            Reload::Apply => info!(self.log, "reloaded"),
+                            Reload::Reconnect => {
                                 warn!(self.log, "reconnecting");
                                 client.send_quit("Reconnecting")?;
                             }
-                        } else {
-                            shutdown = true;
-                            warn!(self.log, "deconfigured");
-                            client.send_quit("Disconnecting")?;
+                            Reload::Deconfigured => {
+                                shutdown = true;
+                                warn!(self.log, "deconfigured"; "pending" => pending.len());
+                            }
                         }
                     } else {
                         shutdown = true;
-                        warn!(self.log, "disconnecting");
+                        warn!(self.log, "disconnecting"; "pending" => pending.len());
+                    }
+
+                    if shutdown {
+                        deadline = Some(Instant::now() + config.shutdown_grace());
+                        if pending.is_empty() {
+                            client.send_quit("Disconnecting")?;
+                            quit_sent = true;
+                        }
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if !quit_sent {
                         client.send_quit("Disconnecting")?;
                     }
+                    warn!(self.log, "disconnect"; "status" => "grace period over", "pending" => pending.len());
+                    break;
+                },
+                _ = pinger.tick(), if registered && netconf.ping_interval_secs.is_some() => {
+                    if let Some((_, sent)) = &ping_sent {
+                        warn!(self.log, "ping"; "status" => "no reply", "waited" => ?sent.elapsed());
+                    }
+                    let token = format!("annoirc-{}", chrono::Utc::now().timestamp_millis());
+                    client.send(Command::PING(token.clone(), None))?;
+                    ping_sent = Some((token, Instant::now()));
+                },
+                _ = tokio::time::sleep_until(identifying.unwrap_or_else(Instant::now)), if identifying.is_some() => {
+                    warn!(self.log, "nickserv"; "status" => "unconfirmed");
+                    identifying = None;
+                    pending.push(join_channels(client.sender(), &netconf, state.warmup.clone()).map(Ok).boxed());
+                },
+                Some(fut) = pending.next() => {
+                    let _ = fut; /* probably cancelled by a concurrency change */
+                    if shutdown && !quit_sent && pending.is_empty() {
+                        client.send_quit("Disconnecting")?;
+                        quit_sent = true;
+                    }
                 },
-                Some(fut) = pending.next() => { let _ = fut; /* probably cancelled by a concurrency change */ },
                 message = stream.next() => {
                     if message.is_none() {
                         break;
@@ -189,87 +1079,383 @@ impl IrcTask {
                         Command::ERROR(ref msg) => {
                             error!(self.log, "irc"; "error" => %msg);
                         },
-                        Command::Response(irc::proto::Response::RPL_ENDOFMOTD, _)
-                        | Command::Response(irc::proto::Response::ERR_NOMOTD, _) => {
-                            self.throttle.success();
-                            warn!(self.log, "connected"; "nick" => client.current_nickname());
+                        Command::Response(irc::proto::Response::RPL_WELCOME, args) if !args.is_empty() => {
+                            nickname = args[0].clone();
                         },
-                        Command::JOIN(ref c, None, None) => {
-                            if let Some(Prefix::Nickname(nick, _, _)) = &message.prefix {
-                                if nick == client.current_nickname() {
-                                    warn!(self.log, "join"; "channel" => c);
+                        Command::NICK(new) if message.source_nickname() == Some(nickname.as_str()) => {
+                            warn!(self.log, "nick"; "old" => &nickname, "new" => new, "tracked" => client.current_nickname() == new);
+                            nickname = new.clone();
+                            if let (Some(Reclaim::Renaming), Some(nickserv)) = (reclaim, &netconf.nickserv) {
+                                if nickname.eq_ignore_ascii_case(&wanted) {
+                                    info!(self.log, "nickserv"; "status" => "identifying");
+                                    client.send(identify_command(nickserv))?;
+                                    reclaim = None;
                                 }
                             }
-                        }
-                        Command::INVITE(target, channel) if target == client.current_nickname() && netconf.channels.contains(channel) => {
-                            warn!(self.log, "invited"; "channel" => channel, "source" => message_source(&message));
-                            // TODO: channel keys
-                            client.send_join(channel)?;
                         },
-                        Command::KICK(channel, target, reason) if target == client.current_nickname() => {
-                            warn!(self.log, "kicked"; "channel" => channel, "reason" => reason, "source" => message_source(&message));
+                        Command::Response(irc::proto::Response::ERR_NICKNAMEINUSE, _) if reclaim == Some(Reclaim::Renaming) => {
+                            warn!(self.log, "nickserv"; "status" => "nick still in use", "nick" => &wanted);
+                            if let Some(nickserv) = &netconf.nickserv {
+                                client.send(identify_command(nickserv))?;
+                            }
+                            reclaim = None;
                         },
-                        Command::PRIVMSG(target, content) => {
-                            if let Some(Prefix::Nickname(nick, _, _)) = &message.prefix {
-                                // Avoid responding to ourselves, CTCPs, coloured text (usually other bots), and any target we're not configured for
-                                if nick == client.current_nickname() || content.starts_with('\x01') || content.contains('\x03') || !netconf.channels.contains(target) {
-                                    continue;
-                                }
-
-                                if content.starts_with(&config.command.prefix) {
-                                    let split = &mut content[config.command.prefix.len()..].split_ascii_whitespace();
-                                    let command = split.next().unwrap_or_default().to_lowercase().to_string();
-                                    let args = itertools::join(split, " ");
-                                    if !command.is_empty() && !args.is_empty() {
-                                        if config.omdb.api_key.is_some() {
-                                            let kind = match &command[..] {
-                                                "imdb" | "omdb" => Some("Any"),
-                                                "film" | "movie" => Some("Movie"),
-                                                "show" | "series" | "tv" => Some("Series"),
-                                                "ep" | "episode" => Some("Episode"),
-                                                "game" => Some("Game"),
-                                                _ => None,
-                                            };
-
-                                            if let Some(kind) = kind {
-                                                if limiter.check_key(&target.clone()).is_err() {
-                                                    warn!(self.log, "ratelimit"; "channel" => target, "source" => nick);
-                                                    continue;
-                                                }
-
-                                                info!(self.log, "omdb"; "kind" => kind, "search" => &args, "channel" => %target, "source" => %nick);
-                                                self.command(BotCommand::Omdb(kind, args.clone()), target.clone(), client.sender()).map(|fut| pending.push(fut));
-                                                continue;
-                                            }
-                                        }
-                                        if config.wolfram.app_id.is_some() && matches!(&command[..], "wolfram" | "calc") {
-                                            if limiter.check_key(&target.clone()).is_err() {
-                                                warn!(self.log, "ratelimit"; "channel" => target, "source" => nick);
-                                                continue;
-                                            }
-
-                                            info!(self.log, "wolfram"; "query" => &args, "channel" => %target, "source" => %nick);
-                                            self.command(BotCommand::Wolfram(args.clone()), target.clone(), client.sender()).map(|fut| pending.push(fut));
-                                            continue;
+                        Command::UserMODE(target, modes) if target == &nickname => {
+                            user_modes.apply(&modes.iter().map(ToString::to_string).collect::<String>());
+                            self.update_exemption(&user_modes, &netconf, &state.warmup);
+                        },
+                        Command::Response(irc::proto::Response::RPL_UMODEIS, args) if args.len() > 1 => {
+                            user_modes = UserModes::default();
+                            user_modes.apply(&args[1]);
+                            self.update_exemption(&user_modes, &netconf, &state.warmup);
+                        },
+                        Command::Response(irc::proto::Response::RPL_ENDOFMOTD, _)
+                        | Command::Response(irc::proto::Response::ERR_NOMOTD, _) => {
+                            self.throttle.success();
+                            registered = true;
+                            state.warmup.start();
+                            if _registration.is_none() {
+                                _registration = Some(self.connections.register(&self.name, output.clone(), state.warmup.clone()));
+                                if let Some(nickserv) = &netconf.nickserv {
+                                    // Identifying waits until we have our own nick back
+                                    if nickserv.ghost && !nickname.eq_ignore_ascii_case(&wanted) {
+                                        info!(self.log, "nickserv"; "status" => "reclaiming", "nick" => &wanted);
+                                        for command in ghost_commands(nickserv, &wanted) {
+                                            client.send(command)?;
                                         }
+                                        reclaim = Some(Reclaim::Ghosting);
+                                    } else {
+                                        info!(self.log, "nickserv"; "status" => "identifying");
+                                        client.send(identify_command(nickserv))?;
                                     }
+                                    identifying = Some(Instant::now() + Duration::from_secs(nickserv.identify_timeout_secs as u64));
                                 }
+                            }
+                            warn!(self.log, "connected"; "nick" => &nickname);
+
+                            if caps.wants_any() {
+                                client.send(Command::CAP(None, CapSubCommand::LS, Some("302".to_string()), None))?;
+                            }
+                        },
+                        _ if identifying.is_some() && netconf.nickserv.as_ref().is_some_and(|nickserv| services_identified(&message, nickserv)) => {
+                            info!(self.log, "nickserv"; "status" => "identified");
+                            identifying = None;
+                            pending.push(join_channels(client.sender(), &netconf, state.warmup.clone()).map(Ok).boxed());
+                        },
+                        _ if reclaim == Some(Reclaim::Ghosting) && netconf.nickserv.as_ref().is_some_and(|nickserv| ghost_gone(&message, nickserv, &wanted)) => {
+                            info!(self.log, "nickserv"; "status" => "freed", "nick" => &wanted);
+                            client.send(Command::NICK(wanted.clone()))?;
+                            reclaim = Some(Reclaim::Renaming);
+                        },
+                        Command::CAP(_, subcommand, param, trailing) => {
+                            let list = cap_list(param, trailing);
+                            match subcommand {
+                                CapSubCommand::DEL => warn!(self.log, "cap"; "removed" => list),
+                                CapSubCommand::NAK => warn!(self.log, "cap"; "rejected" => list),
+                                _ => info!(self.log, "cap"; "subcommand" => subcommand.to_str(), "caps" => list),
+                            }
+
+                            let request = caps.handle(subcommand, list);
+                            if matches!(subcommand, CapSubCommand::LS | CapSubCommand::NEW | CapSubCommand::DEL) {
+                                debug!(self.log, "cap"; "available" => caps.available());
+                            }
+                            // A * parameter marks all but the last line of a long LS reply
+                            if *subcommand == CapSubCommand::LS && param.as_deref() != Some("*") {
+                                let unavailable = caps.unavailable();
+                                if !unavailable.is_empty() {
+                                    warn!(self.log, "cap"; "unavailable" => unavailable.join(" "));
+                                }
+                            }
+                            if matches!(subcommand, CapSubCommand::ACK | CapSubCommand::DEL) {
+                                info!(self.log, "cap"; "enabled" => caps.enabled());
+                            }
+                            if !request.is_empty() {
+                                info!(self.log, "cap"; "requesting" => request.join(" "));
+                                client.send(Command::CAP(None, CapSubCommand::REQ, None, Some(request.join(" "))))?;
+                            }
+                        },
+                        Command::PONG(server, token) => {
+                            let matched = ping_sent
+                                .as_ref()
+                                .filter(|(sent, _)| token.as_ref().unwrap_or(server) == sent)
+                                .map(|(_, at)| at.elapsed());
+                            if let Some(rtt) = matched {
+                                info!(self.log, "pong"; "rtt" => ?rtt);
+                                ping_sent = None;
+                            }
+                        },
+                        Command::JOIN(ref c, _, _) => {
+                            if let Some(Prefix::Nickname(nick, _, _)) = &message.prefix {
+                                if nick == &nickname {
+                                    warn!(self.log, "join"; "channel" => c);
+                                }
+                            }
+                        }
+                        Command::INVITE(target, channel) if target == &nickname && netconf.serves_channel(channel) => {
+                            warn!(self.log, "invited"; "channel" => channel, "source" => message_source(&message));
+                            match netconf.channel_key(channel) {
+                                Some(key) => client.send_join_with_keys::<&str, &str>(channel, key)?,
+                                None => client.send_join(channel)?,
+                            }
+                        },
+                        Command::KICK(channel, target, reason) if target == &nickname => {
+                            warn!(self.log, "kicked"; "channel" => channel, "reason" => reason, "source" => message_source(&message));
+                        },
+                        Command::WALLOPS(text) | Command::NOTICE(_, text) if is_announcement(&message) => {
+                            let text = sanitize(text, 300);
+                            let source = message_source(&message);
+                            if netconf.server_notices.log {
+                                info!(self.log, "announcement"; "source" => source, "text" => &text);
+                            }
+                            if let (true, Some(channel)) = (registered, &netconf.server_notices.channel) {
+                                if !limiter.check(channel) {
+                                    warn!(self.log, "ratelimit"; "channel" => channel, "source" => source);
+                                } else {
+                                    output.privmsg(channel, format_announcement(source, &text, &config.template))?;
+                                }
+                            }
+                        },
+                        // With echo-message the server confirms delivery of what we send by repeating it back
+                        Command::PRIVMSG(target, _) | Command::NOTICE(target, _) if caps.is_enabled(ECHO_MESSAGE) && message.source_nickname() == Some(nickname.as_str()) => {
+                            delivered += 1;
+                            debug!(self.log, "delivered"; "target" => target, "count" => delivered);
+                        },
+                        Command::PRIVMSG(_, content) if ctcp_query(content).is_some_and(|query| query.eq_ignore_ascii_case("VERSION")) => {
+                            if let Some(nick) = message.source_nickname() {
+                                if !limiter.check_ctcp() {
+                                    warn!(self.log, "ratelimit"; "ctcp" => "VERSION", "source" => nick);
+                                } else {
+                                    info!(self.log, "ctcp"; "query" => "VERSION", "source" => nick);
+                                    output.notice(nick, format_ctcp("VERSION", netconf.irc.version()))?;
+                                }
+                            }
+                        },
+                        Command::PRIVMSG(target, content) | Command::NOTICE(target, content) if !shutdown && accepts_command(&message.command, &netconf) => {
+                            if let Some(Prefix::Nickname(nick, _, _)) = &message.prefix {
+                                let (nick, content) = match unwrap_relay(nick, content, &netconf.relay) {
+                                    Some(relayed) => relayed,
+                                    None => continue,
+                                };
+                                let (nick, content) = (&nick, &content);
+                                // Relayed nicks come from message text, so may be any length when shown
+                                let shown = sanitize(nick, config.template.max_nick_bytes as usize);
+
+                                // Avoid responding to ourselves, ignored nicks, CTCPs, coloured text (usually other bots), and any target we're not configured for
+                                if nick == &nickname || netconf.ignores_nick(nick) || netconf.has_fingerprint(content) || content.starts_with('\x01') || content.contains('\x03') || !netconf.serves_channel(target) {
+                                    continue;
+                                }
+
+                                // Everything said in response to this message shares one line budget
+                                let replies = Reply::new(target, &output, &config.command);
 
-                                for url in url_entities(content)
-                                    .into_iter()
-                                    .filter(|url| !config.url.ignore_url_regex.is_match(url.substr(content)))
-                                    .filter_map(|url| parse_url(url.substr(content), config.url.scheme_required).ok())
-                                    .take(config.url.max_per_message as usize)
-                                    .unique()
-                                {
-                                    if limiter.check_key(&target.clone()).is_err() {
-                                        warn!(self.log, "ratelimit"; "channel" => target, "source" => nick);
+                                if let (Some(greeting), true) = (&netconf.greeting, self.first_seen.spoke(target, nick) && netconf.greets(target)) {
+                                    if !limiter.check(target) {
+                                        warn!(self.log, "ratelimit"; "channel" => target, "source" => &shown);
+                                    } else {
+                                        info!(self.log, "greet"; "channel" => %target, "nick" => %shown);
+                                        let (greet, greeting) = (replies.clone(), format_greeting(greeting, target, &shown));
+                                        pending.push(state.warmup.clone().pace().map(move |_| Ok(greet.privmsg(greeting))).boxed());
+                                    }
+                                }
+
+                                // Commands taking arguments are ignored without them, and admin commands from anyone else
+                                let prefix = netconf.command_prefix(target, &config.command);
+                                if let Some((spec, args)) = registry::parse(content, prefix, &config).filter(|(spec, args)| !(spec.needs_args && args.is_empty())).filter(|(spec, _)| !spec.admin_only(&config) || netconf.is_admin(&message)) {
+                                    // Expensive kinds of command may be limited to certain channels
+                                    if let Some(kind) = spec.action.kind().filter(|kind| !config.command.allowed_in(kind, target)) {
+                                        info!(self.log, "restricted"; "kind" => kind, "channel" => %target, "source" => %shown);
+                                        if let Some(notice) = &config.command.restricted_notice {
+                                            replies.notice(notice)?;
+                                        }
+                                    } else if pending.len() >= config.command.max_pending_per_connection as usize {
+                                        warn!(self.log, "overloaded"; "pending" => pending.len(), "channel" => target, "source" => &shown);
+                                    } else if !limiter.check_command(target, spec, netconf.is_admin(&message)) {
+                                        warn!(self.log, "ratelimit"; "channel" => target, "source" => &shown);
+                                    } else {
+                                        if let Some(path) = &config.command.audit_log {
+                                            audit::record(&self.log, path, AuditEntry {
+                                                network: &self.name,
+                                                channel: target,
+                                                source: nick,
+                                                command: spec.names[0],
+                                                args: &args,
+                                            });
+                                        }
+
+                                        let cmd = match spec.action {
+                                            Action::LastUrl => {
+                                                let count = args.parse::<usize>().unwrap_or(3).clamp(1, config.url.history_entries.max(1) as usize);
+                                                info!(self.log, "lasturl"; "count" => count, "channel" => %target, "source" => %shown);
+                                                let recent = state.history.recent(target, count);
+                                                if !recent.is_empty() {
+                                                    replies.privmsg(format_history(&recent))?;
+                                                }
+                                                None
+                                            }
+                                            Action::Stats => {
+                                                info!(self.log, "stats"; "channel" => %target, "source" => %shown);
+                                                replies.privmsg(format_stats(&self.handler.stats, self.connections.len()))?;
+                                                None
+                                            }
+                                            Action::Omdb(kind) => {
+                                                // A bare number picks from the channel's last ambiguous search
+                                                let choice = args.parse::<usize>().ok().filter(|_| kind == "Any").and_then(|n| state.omdb_choices.choose(target, n));
+                                                if let Some(id) = choice {
+                                                    info!(self.log, "omdb"; "choice" => &args, "id" => %id, "channel" => %target, "source" => %shown);
+                                                    Some(BotCommand::OmdbId(id.to_string()))
+                                                } else if omdb::is_imdb_id(&args) {
+                                                    info!(self.log, "omdb"; "id" => &args, "channel" => %target, "source" => %shown);
+                                                    Some(BotCommand::OmdbId(args.clone()))
+                                                } else {
+                                                    info!(self.log, "omdb"; "kind" => kind, "search" => &args, "channel" => %target, "source" => %shown);
+                                                    Some(BotCommand::Omdb(kind, args.clone()))
+                                                }
+                                            }
+                                            Action::OmdbSearch => {
+                                                info!(self.log, "omdb"; "candidates" => &args, "channel" => %target, "source" => %shown);
+                                                Some(BotCommand::OmdbSearch("Any", args.clone()))
+                                            }
+                                            Action::Title => {
+                                                let url = extract_urls(&args, &config.url, netconf.twitter_links(target, &config.twitter)).into_iter().next();
+                                                info!(self.log, "title"; "url" => ?url.as_ref().map(Url::as_str), "channel" => %target, "source" => %shown);
+                                                url.map(|url| BotCommand::Url(url, config.url_options(Some(&netconf), target)))
+                                            }
+                                            Action::Wolfram => {
+                                                info!(self.log, "wolfram"; "query" => &args, "channel" => %target, "source" => %shown);
+                                                Some(BotCommand::Wolfram(args.clone()))
+                                            }
+                                            Action::Learn => {
+                                                let reply = factoid::parse_learn(&args, prefix, &config).and_then(|(key, value)| {
+                                                    info!(self.log, "learn"; "key" => &key, "value" => &value, "channel" => %target, "source" => %shown);
+                                                    let learned = format!("learned {}", key);
+                                                    self.handler.factoids.learn(&self.name, target, key, value, config.factoid.max_per_channel).map(|_| learned)
+                                                });
+                                                replies.privmsg(format!("[\x0303Learn\x0f] {}", reply.unwrap_or_else(|e| e)))?;
+                                                None
+                                            }
+                                            Action::WhatIs => {
+                                                info!(self.log, "whatis"; "key" => &args, "channel" => %target, "source" => %shown);
+                                                let reply = factoid::parse_key(&args, &config).map(|key| match self.handler.factoids.get(&self.name, target, &key) {
+                                                    Some(value) => format_factoid(&key, &value),
+                                                    None => format!("[\x0303WhatIs\x0f] I don't know {}", key),
+                                                });
+                                                replies.privmsg(reply.unwrap_or_else(|e| format!("[\x0303WhatIs\x0f] {}", e)))?;
+                                                None
+                                            }
+                                            Action::Forget => {
+                                                info!(self.log, "forget"; "key" => &args, "channel" => %target, "source" => %shown);
+                                                let reply = factoid::parse_key(&args, &config).and_then(|key| {
+                                                    self.handler.factoids.forget(&self.name, target, &key).map(|known| match known {
+                                                        true => format!("forgot {}", key),
+                                                        false => format!("I don't know {}", key),
+                                                    })
+                                                });
+                                                replies.privmsg(format!("[\x0303Forget\x0f] {}", reply.unwrap_or_else(|e| e)))?;
+                                                None
+                                            }
+                                            Action::Broadcast => {
+                                                let (name, text) = args.split_once(' ').unwrap_or((&args, ""));
+                                                let reply = match config.broadcast.get(name) {
+                                                    Some(_) if text.trim().is_empty() => "usage: broadcast <name> <message>".to_string(),
+                                                    Some(targets) => {
+                                                        info!(self.log, "broadcast"; "name" => name, "channel" => %target, "source" => %shown);
+                                                        match self.connections.broadcast(&self.log, targets, &sanitize(text, LINE_BYTES)).as_slice() {
+                                                            [] => format!("sent to {}", name),
+                                                            missing => format!("sent to {}, except {} which aren't connected", name, missing.join(", ")),
+                                                        }
+                                                    }
+                                                    None => format!("no broadcast named {}", sanitize(name, 32)),
+                                                };
+                                                replies.privmsg(format!("[\x0303Broadcast\x0f] {}", reply))?;
+                                                None
+                                            }
+                                            Action::Unlimit => {
+                                                let channel = args.split_ascii_whitespace().next().unwrap_or_default();
+                                                info!(self.log, "unlimit"; "target" => channel, "channel" => %target, "source" => %shown);
+                                                let channel_name = sanitize(channel, 64);
+                                                let reply = match limiter.reset(channel) {
+                                                    true => format!("reset {}", channel_name),
+                                                    false => format!("{} had nothing to reset", channel_name),
+                                                };
+                                                replies.privmsg(format!("[\x0303Unlimit\x0f] {}", reply))?;
+                                                None
+                                            }
+                                            Action::Why => {
+                                                info!(self.log, "why"; "text" => &args, "channel" => %target, "source" => %shown);
+                                                match explain_url(&args, &config.url, netconf.twitter_links(target, &config.twitter)) {
+                                                    Ok(url) => {
+                                                        if let Some(fut) = self.explain(url, config.url_options(Some(&netconf), target), replies.clone(), state.warmup.clone()) {
+                                                            pending.push(fut.boxed());
+                                                        }
+                                                    }
+                                                    Err(reason) => replies.privmsg(format!("[\x0303Why\x0f] {}", sanitize(&reason, 300)))?,
+                                                }
+                                                None
+                                            }
+                                        };
+
+                                        if let Some(fut) = cmd.and_then(|cmd| self.command(cmd, true, replies.clone(), &state, None)) {
+                                            pending.push(fut.boxed());
+                                        }
+                                    }
+                                } else if let Some(key) = registry::factoid_key(content, prefix, &config).map(str::to_lowercase) {
+                                    if let Some(value) = self.handler.factoids.get(&self.name, target, &key) {
+                                        if !limiter.check(target) {
+                                            warn!(self.log, "ratelimit"; "channel" => target, "source" => &shown);
+                                        } else {
+                                            info!(self.log, "recall"; "key" => &key, "channel" => %target, "source" => %shown);
+                                            replies.privmsg(format_factoid(&key, &value))?;
+                                        }
+                                    }
+                                }
+
+                                if !registry::previews_urls(content, prefix, &config) {
+                                    continue;
+                                }
+
+                                if config.url.min_url_share_percent > 0 && url_share(content, &config.url) < config.url.min_url_share_percent {
+                                    debug!(self.log, "incidental"; "channel" => %target, "source" => %shown);
+                                    continue;
+                                }
+
+                                let (urls, excess) = extract_urls_counting(content, &config.url, netconf.twitter_links(target, &config.twitter));
+                                let last = urls.len().saturating_sub(1);
+                                for (i, url) in urls.into_iter().enumerate() {
+                                    if config.url.per_user_dedupe_secs > 0 && user_dedupe.seen(target, nick, &url, Duration::from_secs(config.url.per_user_dedupe_secs as u64)) {
+                                        info!(self.log, "duplicate"; "url" => %url, "channel" => %target, "source" => %shown);
+                                        continue;
+                                    }
+
+                                    if let (Some(group), true) = (&netconf.group, netconf.group_dedupe_secs > 0) {
+                                        if self.group_dedupe.seen(group, target, &url, Duration::from_secs(netconf.group_dedupe_secs as u64)) {
+                                            info!(self.log, "duplicate"; "url" => %url, "group" => group, "channel" => %target, "source" => %shown);
+                                            continue;
+                                        }
+                                    }
+
+                                    // Don't spend rate limit on a lookup we've no room for
+                                    if pending.len() >= config.command.max_pending_per_connection as usize {
+                                        warn!(self.log, "overloaded"; "pending" => pending.len(), "channel" => target, "source" => &shown);
+                                        break;
+                                    }
+
+                                    if !limiter.check(target) {
+                                        warn!(self.log, "ratelimit"; "channel" => target, "source" => &shown);
                                         break;
                                     }
 
-                                    let cmd = BotCommand::Url(url.clone());
-                                    info!(self.log, "lookup"; "url" => %url, "channel" => %target, "source" => %nick);
-                                    self.command(cmd, target.clone(), client.sender()).map(|fut| pending.push(fut));
+                                    let cmd = BotCommand::Url(url.clone(), config.url_options(Some(&netconf), target));
+                                    info!(self.log, "lookup"; "url" => %url, "channel" => %target, "source" => %shown);
+                                    let note = Some(format!("(+{} more)", excess)).filter(|_| i == last && excess > 0 && config.url.note_excess_urls);
+                                    if let Some(fut) = self.command(cmd, false, replies.clone(), &state, note) {
+                                        pending.push(fut.boxed());
+                                        if config.url.per_user_dedupe_secs > 0 {
+                                            user_dedupe.record(target, nick, &url);
+                                        }
+                                        if let (Some(group), true) = (&netconf.group, netconf.group_dedupe_secs > 0) {
+                                            self.group_dedupe.record(group, target, &url);
+                                        }
+                                    }
                                 }
                             }
                         },
@@ -283,25 +1469,518 @@ impl IrcTask {
         Ok(shutdown)
     }
 
+    /// Run a command, displaying its result.  Explicit commands, as opposed to
+    /// URLs spotted in passing, may also report some failures.
     fn command(
         &self,
         cmd: BotCommand,
-        target: String,
-        sender: Sender,
+        explicit: bool,
+        reply: Reply,
+        state: &ConnectionState,
+        note: Option<String>,
     ) -> Option<
         impl futures::future::Future<Output = Result<Result<()>, futures::channel::oneshot::Canceled>>,
     > {
         let config = self.config.current();
-        self.handler.spawn(cmd).map(move |fut| {
-            fut.map_ok(move |res| {
-                if let Ok(res) = &*res {
-                    display_response(res, &target, sender, config)
-                } else {
+        let name = self.name.clone();
+        let stats = self.handler.stats.clone();
+        let state = state.clone();
+        let warmup = state.warmup.clone();
+        let url = match &cmd {
+            BotCommand::Url(url, _) => Some(url.clone()),
+            _ => None,
+        };
+        let log = self.log.clone();
+        let kind = cmd.kind();
+        self.handler.spawn(cmd).map(move |(fut, origin)| {
+            info!(log, "dispatch"; "kind" => kind, "cached" => origin == Origin::Cache, "channel" => %reply.target);
+            fut.and_then(|res| warmup.pace().map(|_| Ok(res)))
+                .map_ok(move |res| {
+                    if let Err(e) = &*res {
+                        if let (true, Some(limited)) = (
+                            config.command.report_rate_limits,
+                            e.downcast_ref::<RateLimited>(),
+                        ) {
+                            reply.privmsg(format!("[\x0304Error\x0f] {}", limited))?;
+                        }
+                        if let (true, Some(uninterpreted)) =
+                            (explicit, e.downcast_ref::<Uninterpreted>())
+                        {
+                            reply.privmsg(
+                                format!("[\x0303Wolfram\x0f] {}", uninterpreted),
+                            )?;
+                        }
+                        if let (true, true, Some(url), Some(HttpStatus(status))) = (
+                            explicit,
+                            config.url.report_status_on_command,
+                            &url,
+                            e.downcast_ref::<HttpStatus>(),
+                        ) {
+                            reply.privmsg(format_status(*status, url, &config.template))?;
+                        }
+                    }
+                    if let Ok(res) = &*res {
+                        let netconf = config.network.get(&name);
+                        let preview_bytes =
+                            netconf.map_or(LINE_BYTES, |netconf| netconf.preview_bytes(&reply.target));
+                        let locale = config.locale_for(netconf, &reply.target);
+                        display_response(res, &reply, &config, locale, preview_bytes, note.as_deref())?;
+                        if let Info::Url(_) = res {
+                            stats.record_preview();
+                        }
+                        if let Info::Movie(movie) = res {
+                            if !movie.alternatives.is_empty() {
+                                state.omdb_choices.offer(
+                                    &reply.target,
+                                    std::iter::once(&movie.imdb_id)
+                                        .chain(movie.alternatives.iter().map(|c| &c.imdb_id))
+                                        .cloned()
+                                        .collect(),
+                                );
+                            }
+                        }
+                        if let Info::Candidates(candidates) = res {
+                            state.omdb_choices.offer(
+                                &reply.target,
+                                candidates
+                                    .iter()
+                                    .take(MAX_CANDIDATES)
+                                    .map(|c| c.imdb_id.clone())
+                                    .collect(),
+                            );
+                        }
+                        if let (Some(url), Some(title)) = (url, info_title(res)) {
+                            state.history.record(
+                                &reply.target,
+                                url,
+                                title.clone(),
+                                config.url.history_entries as usize,
+                            );
+                        }
+                    }
                     Ok(())
-                }
-            })
+                })
         })
     }
+
+    fn update_exemption(&self, modes: &UserModes, netconf: &NetworkConfig, warmup: &Warmup) {
+        let exempt = modes.any(&netconf.flood_exempt_modes);
+        if exempt != warmup.exempt() {
+            info!(self.log, "flood exemption"; "exempt" => exempt, "modes" => ?modes.0);
+            warmup.set_exempt(exempt);
+        }
+    }
+
+    /// Look up a URL as if previewing it, but report what happened instead
+    fn explain(
+        &self,
+        url: Url,
+        options: UrlOptions,
+        reply: Reply,
+        warmup: Warmup,
+    ) -> Option<
+        impl futures::future::Future<Output = Result<Result<()>, futures::channel::oneshot::Canceled>>,
+    > {
+        let cmd = BotCommand::Url(url.clone(), options);
+        self.handler.spawn(cmd).map(move |(fut, _)| {
+            fut.and_then(|res| warmup.pace().map(|_| Ok(res)))
+                .map_ok(move |res| {
+                    let outcome = match &*res {
+                        Ok(info) => match info_title(info) {
+                            Some(title) => format!("would preview \"{}\"", title.trunc(200)),
+                            None => "would preview".to_string(),
+                        },
+                        Err(e) => format!("lookup failed: {}", e),
+                    };
+                    reply.privmsg(format!(
+                        "[\x0303Why\x0f] {}: {}",
+                        sanitize(url.as_str(), 100),
+                        outcome
+                    ))?;
+                    Ok(())
+                })
+        })
+    }
+}
+
+lazy_static! {
+    /// How services tell us we've identified: Atheme, Anope, and others
+    static ref IDENTIFIED: Regex = Regex::new(
+        r"(?i)\b(?:you are now (?:identified|recognized|logged in)|password accepted)"
+    )
+    .unwrap();
+    /// How they tell us they've freed a nick
+    static ref GHOSTED: Regex = Regex::new(r"(?i)\bhas been (?:ghosted|killed|released)").unwrap();
+}
+
+/// How far we've got reclaiming our nick from whoever else has it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reclaim {
+    /// Waiting for services to confirm they've freed it
+    Ghosting,
+    /// Waiting for the server to confirm our change to it
+    Renaming,
+}
+
+/// Ask services to free our nick, from another user or their enforcer
+fn ghost_commands(nickserv: &NickServConfig, wanted: &str) -> Vec<Command> {
+    ["GHOST", "RELEASE"]
+        .iter()
+        .map(|verb| {
+            Command::PRIVMSG(
+                nickserv.service.clone(),
+                format!("{} {} {}", verb, wanted, nickserv.password),
+            )
+        })
+        .collect()
+}
+
+fn identify_command(nickserv: &NickServConfig) -> Command {
+    Command::PRIVMSG(
+        nickserv.service.clone(),
+        format!("IDENTIFY {}", nickserv.password),
+    )
+}
+
+/// Whether our nick has been freed: services say so, or its holder quits
+fn ghost_gone(message: &Message, nickserv: &NickServConfig, wanted: &str) -> bool {
+    match &message.command {
+        Command::QUIT(_) => message
+            .source_nickname()
+            .is_some_and(|nick| nick.eq_ignore_ascii_case(wanted)),
+        Command::NOTICE(_, text) => {
+            message
+                .source_nickname()
+                .is_some_and(|nick| nick.eq_ignore_ascii_case(&nickserv.service))
+                && GHOSTED.is_match(&sanitize(text, LINE_BYTES))
+        }
+        _ => false,
+    }
+}
+
+/// Whether services have confirmed we're identified, by numeric or NOTICE
+fn services_identified(message: &Message, nickserv: &NickServConfig) -> bool {
+    match &message.command {
+        Command::Response(irc::proto::Response::RPL_LOGGEDIN, _) => true,
+        Command::NOTICE(_, text) => {
+            message
+                .source_nickname()
+                .is_some_and(|nick| nick.eq_ignore_ascii_case(&nickserv.service))
+                && IDENTIFIED.is_match(&sanitize(text, LINE_BYTES))
+        }
+        _ => false,
+    }
+}
+
+/// Join our configured channels, with any keys, as fast as warmup allows
+fn join_channels(
+    sender: Sender,
+    netconf: &NetworkConfig,
+    warmup: Warmup,
+) -> impl std::future::Future<Output = Result<()>> {
+    let channels = netconf
+        .irc
+        .channels()
+        .iter()
+        .map(|channel| {
+            let key = netconf.channel_key(channel).map(str::to_string);
+            (channel.to_string(), key)
+        })
+        .collect::<Vec<_>>();
+
+    async move {
+        for (channel, key) in channels {
+            warmup.clone().pace().await;
+            match key {
+                Some(key) => sender.send_join_with_keys::<&str, &str>(&channel, &key)?,
+                None => sender.send_join(&channel)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_join_channels_paced() {
+    let mut netconf = NetworkConfig {
+        warmup_secs: 60,
+        warmup_messages_per_minute: Some(1),
+        ..NetworkConfig::default()
+    };
+    netconf.irc.nickname = Some("annoirc".to_string());
+    netconf.irc.channels = vec!["#one".to_string(), "#two".to_string()];
+    netconf.irc.use_mock_connection = true;
+    let client = Client::from_config(netconf.irc.clone()).await.unwrap();
+
+    // Joins share warmup's allowance with everything else we send
+    let warmup = Warmup::new(&netconf);
+    warmup.start();
+    let join = join_channels(client.sender(), &netconf, warmup.clone());
+    assert!(tokio::time::timeout(Duration::from_millis(10), join)
+        .await
+        .is_err());
+
+    warmup.set_exempt(true);
+    join_channels(client.sender(), &netconf, warmup)
+        .await
+        .unwrap();
+}
+
+#[test]
+fn test_nickserv() {
+    let nickserv = NickServConfig {
+        password: "hunter2".to_string(),
+        ..NickServConfig::default()
+    };
+    assert_eq!(
+        identify_command(&nickserv),
+        Command::PRIVMSG("NickServ".to_string(), "IDENTIFY hunter2".to_string())
+    );
+    assert_eq!(
+        ghost_commands(&nickserv, "annobot"),
+        vec![
+            Command::PRIVMSG("NickServ".to_string(), "GHOST annobot hunter2".to_string()),
+            Command::PRIVMSG(
+                "NickServ".to_string(),
+                "RELEASE annobot hunter2".to_string()
+            ),
+        ]
+    );
+
+    let msg = |prefix, command, args| Message::new(Some(prefix), command, args).unwrap();
+    let identified = |message| services_identified(&message, &nickserv);
+    assert!(identified(msg(
+        "NickServ!services@services.example",
+        "NOTICE",
+        vec!["annobot", "You are now identified for \x02annobot\x02."]
+    )));
+    assert!(identified(msg(
+        "nickserv!services@services.example",
+        "NOTICE",
+        vec!["annobot", "Password accepted - you are now recognized."]
+    )));
+    assert!(identified(msg(
+        "irc.example",
+        "900",
+        vec![
+            "annobot",
+            "annobot!bot@host",
+            "annobot",
+            "You are now logged in"
+        ]
+    )));
+    assert!(!identified(msg(
+        "NickServ!services@services.example",
+        "NOTICE",
+        vec!["annobot", "Invalid password for annobot."]
+    )));
+    assert!(!identified(msg(
+        "prankster!u@host",
+        "NOTICE",
+        vec!["annobot", "You are now identified for annobot."]
+    )));
+
+    let gone = |message| ghost_gone(&message, &nickserv, "annobot");
+    assert!(gone(msg(
+        "NickServ!services@services.example",
+        "NOTICE",
+        vec!["annobot_", "\x02annobot\x02 has been ghosted."]
+    )));
+    assert!(gone(msg(
+        "NickServ!services@services.example",
+        "NOTICE",
+        vec!["annobot_", "Ghost with your nick has been killed."]
+    )));
+    assert!(gone(msg("AnnoBot!u@host", "QUIT", vec!["Killed"])));
+    assert!(!gone(msg("someone!u@host", "QUIT", vec!["Bye"])));
+    assert!(!gone(msg(
+        "NickServ!services@services.example",
+        "NOTICE",
+        vec!["annobot_", "Access denied."]
+    )));
+    assert!(!gone(msg(
+        "prankster!u@host",
+        "NOTICE",
+        vec!["annobot_", "annobot has been ghosted."]
+    )));
+}
+
+/// Whether a message is a WALLOPS or a NOTICE from a server, as opposed to a user
+fn is_announcement(message: &Message) -> bool {
+    match message.command {
+        Command::WALLOPS(_) => true,
+        Command::NOTICE(_, _) => matches!(message.prefix, Some(Prefix::ServerName(_))),
+        _ => false,
+    }
+}
+
+#[test]
+fn test_is_announcement() {
+    let msg = |prefix, command, args| Message::new(Some(prefix), command, args).unwrap();
+    assert!(is_announcement(&msg(
+        "irc.example",
+        "NOTICE",
+        vec!["*", "Looking up your hostname"]
+    )));
+    assert!(is_announcement(&msg(
+        "oper!o@staff",
+        "WALLOPS",
+        vec!["Rebooting soon"]
+    )));
+    assert!(!is_announcement(&msg(
+        "nick!u@host",
+        "NOTICE",
+        vec!["annobot", "hi"]
+    )));
+    assert!(!is_announcement(&msg(
+        "irc.example",
+        "PRIVMSG",
+        vec!["annobot", "hi"]
+    )));
+}
+
+/// Relay an announcement, its text cut short to leave room for the source
+fn format_announcement(source: &str, text: &str, config: &TemplateConfig) -> String {
+    let tag = format!(
+        "[\x0307{}\x0f] ",
+        sanitize(source, config.max_nick_bytes as usize)
+    );
+    let room = LINE_BYTES.saturating_sub(tag.len() + "…".len());
+    format!("{}{}", tag, sanitize(text, room))
+}
+
+#[test]
+fn test_format_announcement() {
+    let mut config = TemplateConfig::default();
+    assert_eq!(
+        format_announcement("irc.example", "Rebooting soon", &config),
+        "[\x0307irc.example\x0f] Rebooting soon"
+    );
+
+    config.max_nick_bytes = u8::MAX;
+    let line = format_announcement(&"s".repeat(300), &"t".repeat(300), &config);
+    assert!(line.starts_with(&format!("[\x0307{}…\x0f] ttt", "s".repeat(255))));
+    assert!(line.len() <= LINE_BYTES, "{} bytes", line.len());
+}
+
+/// Whether a message is a candidate for command and URL processing.  NOTICEs
+/// are only considered if the network opts in, as bots commonly use them, and
+/// passive networks consider nothing.
+fn accepts_command(command: &Command, netconf: &NetworkConfig) -> bool {
+    if netconf.passive {
+        return false;
+    }
+
+    match command {
+        Command::PRIVMSG(_, _) => true,
+        Command::NOTICE(_, _) => netconf.process_notices,
+        _ => false,
+    }
+}
+
+#[test]
+fn test_accepts_command() {
+    let privmsg = Command::PRIVMSG("#chan".to_string(), "hi".to_string());
+    let notice = Command::NOTICE("#chan".to_string(), "hi".to_string());
+
+    let netconf = NetworkConfig::default();
+    assert!(accepts_command(&privmsg, &netconf));
+    assert!(!accepts_command(&notice, &netconf));
+
+    let netconf = NetworkConfig {
+        process_notices: true,
+        ..NetworkConfig::default()
+    };
+    assert!(accepts_command(&privmsg, &netconf));
+    assert!(accepts_command(&notice, &netconf));
+
+    let netconf = NetworkConfig {
+        passive: true,
+        process_notices: true,
+        ..NetworkConfig::default()
+    };
+    assert!(!accepts_command(&privmsg, &netconf));
+    assert!(!accepts_command(&notice, &netconf));
+}
+
+/// The query of a CTCP request such as `\x01VERSION\x01`
+fn ctcp_query(content: &str) -> Option<&str> {
+    let inner = content.strip_prefix('\x01')?;
+    let inner = inner.strip_suffix('\x01').unwrap_or(inner);
+    inner.split(' ').next().filter(|query| !query.is_empty())
+}
+
+/// A CTCP reply, stripped of anything that would end it or the line early
+fn format_ctcp(query: &str, reply: &str) -> String {
+    let reply: String = reply
+        .chars()
+        .filter(|c| !matches!(c, '\x01' | '\r' | '\n' | '\0'))
+        .collect();
+    format!("\x01{} {}\x01", query, reply)
+}
+
+#[test]
+fn test_ctcp_version() {
+    assert_eq!(ctcp_query("\x01VERSION\x01"), Some("VERSION"));
+    assert_eq!(ctcp_query("\x01version"), Some("version"));
+    assert_eq!(ctcp_query("\x01PING 12345\x01"), Some("PING"));
+    assert_eq!(ctcp_query("\x01\x01"), None);
+    assert_eq!(ctcp_query("VERSION"), None);
+
+    assert_eq!(
+        format_ctcp("VERSION", "annoirc 0.1.0"),
+        "\x01VERSION annoirc 0.1.0\x01"
+    );
+    assert_eq!(
+        format_ctcp("VERSION", "sneaky\x01\r\nQUIT"),
+        "\x01VERSION sneakyQUIT\x01"
+    );
+}
+
+/// The real author and text of a message, which may have been relayed by a
+/// bridge bot, or None if relayed messages are to be skipped
+fn unwrap_relay(nick: &str, content: &str, relay: &RelayConfig) -> Option<(String, String)> {
+    if !relay.is_relay(nick) {
+        return Some((nick.to_string(), content.to_string()));
+    }
+
+    match relay.format.0.captures(content) {
+        Some(_) if relay.skip => None,
+        Some(cap) => Some((
+            // Bridges often break up nicks to avoid highlighting people
+            cap["nick"].replace('\u{200b}', ""),
+            cap["text"].to_string(),
+        )),
+        None => Some((nick.to_string(), content.to_string())),
+    }
+}
+
+#[test]
+fn test_unwrap_relay() {
+    let mut relay = RelayConfig {
+        nicks: vec!["bridge".to_string()],
+        ..RelayConfig::default()
+    };
+    assert_eq!(
+        unwrap_relay("alice", "<bob> hi", &relay),
+        Some(("alice".to_string(), "<bob> hi".to_string()))
+    );
+    assert_eq!(
+        unwrap_relay("Bridge", "<b\u{200b}ob> https://example.com", &relay),
+        Some(("bob".to_string(), "https://example.com".to_string()))
+    );
+    assert_eq!(
+        unwrap_relay("bridge", "bob joined", &relay),
+        Some(("bridge".to_string(), "bob joined".to_string()))
+    );
+
+    // Relayed nicks are bounded before use, as they come from message text
+    let (nick, _) = unwrap_relay("bridge", &format!("<{}> hi", "b".repeat(300)), &relay).unwrap();
+    let nick = sanitize(&nick, TemplateConfig::default().max_nick_bytes as usize);
+    assert_eq!(nick, format!("{}…", "b".repeat(50)));
+
+    relay.skip = true;
+    assert_eq!(unwrap_relay("bridge", "<bob> hi", &relay), None);
 }
 
 fn message_source(msg: &Message) -> &str {
@@ -314,64 +1993,434 @@ fn message_source(msg: &Message) -> &str {
 
 fn display_response(
     info: &Info,
-    target: &str,
-    sender: Sender,
-    config: Arc<BotConfig>,
+    reply: &Reply,
+    config: &BotConfig,
+    locale: &LocaleConfig,
+    preview_bytes: usize,
+    note: Option<&str>,
 ) -> Result<()> {
-    match &info {
-        Info::Url(info) => {
-            let host = sanitize(info.url.host_str().unwrap_or(""), 30);
-            sender.send_privmsg(
-                target,
-                format!(
-                    "[\x0303\x02\x02{}\x0f] \x0300\x02\x02{}\x0f",
-                    host,
-                    info.title.trunc(380)
-                ),
-            )?;
-            if let (true, Some(desc)) = (config.url.include_description, &info.desc) {
-                sender.send_privmsg(
-                    target,
-                    format!(
-                        "[\x0303{}\x02\x02\x0f] \x0300\x02\x02{}\x0f",
-                        host,
-                        desc.trunc(380)
-                    ),
-                )?;
-            }
-        }
-        Info::Movie(movie) => {
-            sender.send_privmsg(target, format_movie(movie))?;
-        }
-        Info::YouTube(item) => {
-            sender.send_privmsg(target, format_youtube(item))?;
-        }
-        Info::Wolfram(response) => {
-            for pod in format_wolfram(response) {
-                sender.send_privmsg(target, pod)?;
-            }
-        }
+    let mut lines = format_response(info, config, locale, preview_bytes);
+
+    if let (Some(note), Some(last)) = (note, lines.last_mut()) {
+        last.push(' ');
+        last.push_str(note);
+    }
+
+    for line in lines {
+        reply.privmsg(line)?;
     }
 
     Ok(())
 }
 
-fn format_movie(movie: &Movie) -> String {
+/// The lines we'd send to IRC for a result
+pub fn format_response(
+    info: &Info,
+    config: &BotConfig,
+    locale: &LocaleConfig,
+    preview_bytes: usize,
+) -> Vec<String> {
+    match info {
+        Info::Url(info) => format_url_info(info, config, preview_bytes),
+        Info::Movie(movie) => vec![format_movie(movie, locale)],
+        Info::Candidates(candidates) => vec![format_candidates(candidates)],
+        Info::YouTube(item) => vec![format_youtube(item, &config.template, locale)],
+        Info::Wolfram(response) => format_wolfram(response),
+    }
+}
+
+fn format_url_info(info: &UrlInfo, config: &BotConfig, max_bytes: usize) -> Vec<String> {
+    let host = sanitize(
+        info.url.host_str().unwrap_or(""),
+        config.template.max_host_bytes as usize,
+    );
+    let desc = info
+        .desc
+        .as_ref()
+        .filter(|_| config.url.include_description);
+
+    if let Some(desc) = desc.filter(|_| config.url.combine_lines) {
+        // Measure the line as sent, tag and formatting codes included
+        let combined = format!(
+            "[\x0303\x02\x02{}\x0f] \x0300\x02\x02{}\x0f{}{}",
+            host, info.title, config.url.combined_separator, desc
+        );
+        if combined.len() <= max_bytes {
+            return vec![combined];
+        }
+    }
+
+    // A long host leaves less room for the text, and truncation adds an ellipsis
+    let overhead = format!("[\x0303\x02\x02{}\x0f] \x0300\x02\x02\x0f…", host).len();
+    let max_bytes = max_bytes.min(LINE_BYTES.saturating_sub(overhead));
+
+    std::iter::once(format!(
+        "[\x0303\x02\x02{}\x0f] \x0300\x02\x02{}\x0f",
+        host,
+        info.title.trunc(max_bytes)
+    ))
+    .chain(desc.map(|desc| {
+        format!(
+            "[\x0303{}\x02\x02\x0f] \x0300\x02\x02{}\x0f",
+            host,
+            desc.trunc(max_bytes)
+        )
+    }))
+    .collect()
+}
+
+#[test]
+fn test_format_url_info() {
+    let mut info = UrlInfo {
+        url: Url::parse("https://example.com/").unwrap(),
+        title: "Example".into(),
+        desc: Some("An example page".into()),
+    };
+    let mut config = BotConfig::default();
+    assert_eq!(format_url_info(&info, &config, LINE_BYTES).len(), 2);
+
+    config.url.combine_lines = true;
+    assert_eq!(
+        format_url_info(&info, &config, LINE_BYTES),
+        vec!["[\x0303\x02\x02example.com\x0f] \x0300\x02\x02Example\x0f - An example page"]
+    );
+
+    // The tag and formatting count towards the limit, not just the text
+    let combined = format_url_info(&info, &config, LINE_BYTES).remove(0);
+    assert_eq!(
+        format_url_info(&info, &config, combined.len()),
+        vec![combined.clone()]
+    );
+    assert_eq!(format_url_info(&info, &config, combined.len() - 1).len(), 2);
+
+    info.desc = Some("long ".repeat(80).into());
+    assert_eq!(format_url_info(&info, &config, LINE_BYTES).len(), 2);
+
+    config.url.include_description = false;
+    assert_eq!(format_url_info(&info, &config, LINE_BYTES).len(), 1);
+
+    config.url.include_description = true;
+    info.title = "A rather long title".into();
+    assert_eq!(
+        format_url_info(&info, &config, 10)[0],
+        "[\x0303\x02\x02example.com\x0f] \x0300\x02\x02A rather l…\x0f"
+    );
+
+    config.template.max_host_bytes = 10;
+    info.url = Url::parse("https://a-very-long-subdomain.example.com/").unwrap();
+    assert_eq!(
+        format_url_info(&info, &config, LINE_BYTES)[0],
+        "[\x0303\x02\x02a-very-lon…\x0f] \x0300\x02\x02A rather long title\x0f"
+    );
+
+    config.url.combine_lines = false;
+    config.template.max_host_bytes = u8::MAX;
+    let host = format!("{}example.com", format!("{}.", "a".repeat(60)).repeat(5));
+    info.url = Url::parse(&format!("https://{}/", host)).unwrap();
+    info.title = "t".repeat(450).into();
+    info.desc = Some("d".repeat(450).into());
+    let lines = format_url_info(&info, &config, LINE_BYTES);
+    assert_eq!(lines.len(), 2);
+    for line in lines {
+        assert!(line.contains(&format!("{}…", &host[..255])));
+        assert!(line.len() <= LINE_BYTES, "{} bytes", line.len());
+    }
+}
+
+fn info_title(info: &Info) -> Option<&IrcString> {
+    match info {
+        Info::Url(info) => Some(&info.title),
+        Info::Movie(movie) => Some(&movie.title),
+        Info::Candidates(_) => None,
+        Info::YouTube(item) => Some(&item.title),
+        Info::Wolfram(_) => None,
+    }
+}
+
+fn format_factoid(key: &str, value: &str) -> String {
+    format!("[\x0303{}\x0f] {}", key, value)
+}
+
+fn format_history(entries: &[HistoryEntry]) -> String {
+    let list = entries
+        .iter()
+        .enumerate()
+        .map(|(i, (url, title))| {
+            format!(
+                "{}. \x0303{}\x0f \x0300\x02\x02{}\x0f",
+                i + 1,
+                sanitize(url.as_str(), 80),
+                title.trunc(40)
+            )
+        })
+        .join(" | ");
+
+    format!("[\x0303History\x0f] {}", list)
+}
+
+fn format_movie(movie: &Movie, locale: &LocaleConfig) -> String {
     format!(
         "[\x0303IMDB\x0f] \x0304{title}\x0f ({released}) [{rating}/10 with {votes} votes, Metascore: {metascore}] [{rated}] [{genre}] \x0303https://www.imdb.com/title/{imdb_id}\x0f - \x0300\x02\x02{plot}\x0f",
         title = movie.title.trunc(30),
-        released = movie.released,
+        released = movie_dates(movie),
         rating = movie.imdb_rating,
-        votes = movie.imdb_votes,
+        votes = regroup(&movie.imdb_votes, locale.numbers),
         metascore = movie.metascore,
         rated = movie.rated,
         genre = movie.genre,
         imdb_id = movie.imdb_id,
         plot = movie.plot,
+    ) + &format_alternatives(movie)
+}
+
+/// Regroup the digits of a number OMDb formatted in English, e.g. "1,234"
+fn regroup(number: &str, locale: Locale) -> String {
+    match number.replace(',', "").parse::<u64>() {
+        Ok(n) => n.to_formatted_string(&locale),
+        Err(_) => number.to_string(),
+    }
+}
+
+/// When a title came out, or the years a series ran for
+fn movie_dates(movie: &Movie) -> &str {
+    match movie.kind {
+        omdb::Kind::Series if !matches!(&*movie.year, "" | "N/A") => &movie.year,
+        _ => &movie.released,
+    }
+}
+
+#[test]
+fn test_format_movie_dates() {
+    let mut movie = Movie {
+        title: "Stranger Things".into(),
+        kind: omdb::Kind::Series,
+        year: "2016–2025".into(),
+        plot: "".into(),
+        rated: "".into(),
+        released: "15 Jul 2016".into(),
+        runtime: "".into(),
+        genre: "".into(),
+        director: "".into(),
+        imdb_rating: "".into(),
+        imdb_votes: "".into(),
+        imdb_id: "".into(),
+        metascore: "".into(),
+        alternatives: vec![],
+    };
+    assert!(format_movie(&movie, &LocaleConfig::default()).contains("(2016–2025)"));
+
+    movie.year = "N/A".into();
+    assert!(format_movie(&movie, &LocaleConfig::default()).contains("(15 Jul 2016)"));
+
+    movie.kind = omdb::Kind::Movie;
+    movie.year = "2016".into();
+    assert!(format_movie(&movie, &LocaleConfig::default()).contains("(15 Jul 2016)"));
+}
+
+/// How many search results to list
+const MAX_CANDIDATES: usize = 5;
+
+fn format_candidates(candidates: &[Candidate]) -> String {
+    if candidates.is_empty() {
+        return "[\x0303IMDB\x0f] No matches".to_string();
+    }
+
+    const TAG: &str = "[\x0303IMDB\x0f] ";
+    const SUFFIX: &str = " - pick with .imdb <n>";
+    const SEPARATOR: &str = " | ";
+
+    // Entries are added while the whole line, tag and suffix included, fits
+    let mut len = TAG.len() + SUFFIX.len();
+    let list = candidates
+        .iter()
+        .take(MAX_CANDIDATES)
+        .enumerate()
+        .map(|(i, c)| {
+            format!(
+                "{}. \x0304{}\x0f ({}, {}) {}",
+                i + 1,
+                c.title.trunc(30),
+                c.year.trunc(12),
+                omdb::kind_name(c.kind),
+                c.imdb_id.trunc(12)
+            )
+        })
+        .enumerate()
+        .take_while(|(i, entry)| {
+            len += entry.len() + if *i == 0 { 0 } else { SEPARATOR.len() };
+            len <= LINE_BYTES
+        })
+        .map(|(_, entry)| entry)
+        .join(SEPARATOR);
+
+    format!("{}{}{}", TAG, list, SUFFIX)
+}
+
+#[test]
+fn test_format_candidates() {
+    let candidates = vec![
+        Candidate {
+            title: "The Thing".into(),
+            year: "1982".into(),
+            imdb_id: "tt0084787".into(),
+            kind: omdb::Kind::Movie,
+        },
+        Candidate {
+            title: "The Thing".into(),
+            year: "2011".into(),
+            imdb_id: "tt0905372".into(),
+            kind: omdb::Kind::Movie,
+        },
+    ];
+    assert_eq!(
+        format_candidates(&candidates),
+        "[\x0303IMDB\x0f] 1. \x0304The Thing\x0f (1982, movie) tt0084787 | 2. \x0304The Thing\x0f (2011, movie) tt0905372 - pick with .imdb <n>"
+    );
+
+    let many = vec![
+        Candidate {
+            title: "x".repeat(30).into(),
+            year: "2000".into(),
+            imdb_id: "tt0000001".into(),
+            kind: omdb::Kind::Series,
+        };
+        MAX_CANDIDATES
+    ];
+    let line = format_candidates(&many);
+    assert!(line.contains("5. \x0304xxx"));
+    assert!(line.len() <= LINE_BYTES, "{} bytes", line.len());
+
+    // OMDb's other fields are bounded too
+    let odd = vec![
+        Candidate {
+            title: "x".repeat(30).into(),
+            year: "1".repeat(100).into(),
+            imdb_id: "t".repeat(100).into(),
+            kind: omdb::Kind::Movie,
+        };
+        MAX_CANDIDATES
+    ];
+    let line = format_candidates(&odd);
+    assert!(line.contains(&format!("({}…, movie) {}…", "1".repeat(12), "t".repeat(12))));
+    assert!(line.len() <= LINE_BYTES, "{} bytes", line.len());
+    assert_eq!(format_candidates(&[]), "[\x0303IMDB\x0f] No matches");
+}
+
+fn format_stats(stats: &Stats, networks: usize) -> String {
+    let commands = stats.commands();
+    let cached = (stats.cache_hits() * 100)
+        .checked_div(commands)
+        .unwrap_or(0);
+    format!(
+        "[\x0303Stats\x0f] up {} | {} network{} | {} commands, {}% cached | {} URLs previewed",
+        format_uptime(stats.uptime()),
+        networks,
+        if networks == 1 { "" } else { "s" },
+        commands.to_formatted_string(&Locale::en),
+        cached,
+        stats.previews().to_formatted_string(&Locale::en)
     )
 }
 
-fn format_youtube(item: &YouTube) -> String {
+fn format_uptime(uptime: Duration) -> String {
+    let mins = uptime.as_secs() / 60;
+    match (mins / 1440, mins / 60 % 24, mins % 60) {
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, _) => format!("{}d {}h", d, h),
+    }
+}
+
+#[test]
+fn test_format_stats() {
+    assert_eq!(format_uptime(Duration::from_secs(59)), "0m");
+    assert_eq!(format_uptime(Duration::from_secs(3 * 3600 + 120)), "3h 2m");
+    assert_eq!(
+        format_uptime(Duration::from_secs(2 * 86_400 + 5 * 3600 + 120)),
+        "2d 5h"
+    );
+
+    let stats = Stats::default();
+    stats.record_preview();
+    assert_eq!(
+        format_stats(&stats, 1),
+        "[\x0303Stats\x0f] up 0m | 1 network | 0 commands, 0% cached | 1 URLs previewed"
+    );
+}
+
+fn format_alternatives(movie: &Movie) -> String {
+    match movie.alternatives.len() {
+        0 => String::new(),
+        1 => " (1 other match: .imdb 2)".to_string(),
+        n => format!(" ({} other matches: .imdb 2-{})", n, n + 1),
+    }
+}
+
+/// Render a date according to the configured `time_format`, at the locale's
+/// offset if it has one
+fn format_date<Tz: TimeZone>(
+    date: &DateTime<Tz>,
+    config: &TemplateConfig,
+    locale: &LocaleConfig,
+) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    match &config.time_format {
+        TimeFormat::Absolute(pattern) => match locale.utc_offset {
+            Some(offset) => date.with_timezone(&offset).format(pattern).to_string(),
+            None => date.format(pattern).to_string(),
+        },
+        TimeFormat::Relative => relative_time(date.with_timezone(&Utc), Utc::now()),
+    }
+}
+
+fn relative_time(date: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let delta = now.signed_duration_since(date);
+    let secs = delta.num_seconds().abs();
+
+    let (n, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86_399 => (secs / 3600, "hour"),
+        86_400..=2_629_799 => (secs / 86_400, "day"),
+        2_629_800..=31_557_599 => (secs / 2_629_800, "month"),
+        _ => (secs / 31_557_600, "year"),
+    };
+    let plural = if n == 1 { "" } else { "s" };
+
+    if delta.num_seconds() < 0 {
+        format!("in {} {}{}", n, unit, plural)
+    } else {
+        format!("{} {}{} ago", n, unit, plural)
+    }
+}
+
+#[test]
+fn test_relative_time() {
+    let now = Utc.with_ymd_and_hms(2021, 6, 15, 12, 0, 0).unwrap();
+    let ago = |secs| relative_time(now - chrono::Duration::seconds(secs), now);
+
+    assert_eq!(ago(30), "just now");
+    assert_eq!(ago(60), "1 minute ago");
+    assert_eq!(ago(3 * 3600 + 5), "3 hours ago");
+    assert_eq!(ago(3 * 86_400), "3 days ago");
+    assert_eq!(ago(400 * 86_400), "1 year ago");
+    assert_eq!(ago(-2 * 86_400), "in 2 days");
+
+    let config = TemplateConfig::default();
+    let mut locale = LocaleConfig::default();
+    assert_eq!(format_date(&now, &config, &locale), "2021-06-15");
+
+    let config = TemplateConfig {
+        time_format: TimeFormat::Absolute("%F %H:%M".to_string()),
+        ..TemplateConfig::default()
+    };
+    locale.utc_offset = chrono::FixedOffset::east_opt(3600);
+    assert_eq!(format_date(&now, &config, &locale), "2021-06-15 13:00");
+}
+
+fn format_youtube(item: &YouTube, config: &TemplateConfig, locale: &LocaleConfig) -> String {
     let duration = item.duration;
     let seconds = duration.as_secs() % 60;
     let minutes = (duration.as_secs() / 60) % 60;
@@ -388,13 +2437,68 @@ fn format_youtube(item: &YouTube) -> String {
         title = item.title.trunc(40),
         desc = item.description.trunc(200),
         channel = item.channel.trunc(16),
-        views = item.views.to_formatted_string(&Locale::en),
-        likes = item.likes.to_formatted_string(&Locale::en),
-        date = item.published_at.map(|d| d.format(" @ %F").to_string()).unwrap_or_default(),
+        views = item.views.to_formatted_string(&locale.numbers),
+        likes = item.likes.to_formatted_string(&locale.numbers),
+        date = item
+            .published_at
+            .map(|d| format!(" @ {}", format_date(&d, config, locale)))
+            .unwrap_or_default(),
         duration = duration,
     )
 }
 
+#[test]
+fn test_format_youtube_locale() {
+    let item = YouTube {
+        id: "dQw4w9WgXcQ".into(),
+        title: "Never Gonna Give You Up".into(),
+        description: "The official video".into(),
+        published_at: DateTime::parse_from_rfc3339("2009-10-25T23:30:00+00:00").ok(),
+        duration: Duration::from_secs(213),
+        channel: "Rick Astley".into(),
+        views: 1_234_567,
+        likes: 8_910,
+    };
+    let config = TemplateConfig::default();
+    let locale = LocaleConfig {
+        numbers: Locale::de,
+        utc_offset: chrono::FixedOffset::east_opt(3600),
+        language: None,
+    };
+
+    let line = format_youtube(&item, &config, &LocaleConfig::default());
+    assert!(line.contains("@ 2009-10-25]"));
+    assert!(line.contains("1,234,567 views ❤️8,910"));
+
+    let line = format_youtube(&item, &config, &locale);
+    assert!(line.contains("@ 2009-10-26]"));
+    assert!(line.contains("1.234.567 views ❤️8.910"));
+    assert_eq!(regroup("1,234,567", Locale::de), "1.234.567");
+    assert_eq!(regroup("N/A", Locale::de), "N/A");
+}
+
+fn format_status(status: reqwest::StatusCode, url: &Url, config: &TemplateConfig) -> String {
+    format!(
+        "[\x0304{}\x0f] {} - \x0303{}\x0f",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Unknown"),
+        sanitize(url.host_str().unwrap_or(""), config.max_host_bytes as usize)
+    )
+}
+
+#[test]
+fn test_format_status() {
+    let url = Url::parse("https://example.com/gone").unwrap();
+    assert_eq!(
+        format_status(
+            reqwest::StatusCode::NOT_FOUND,
+            &url,
+            &TemplateConfig::default()
+        ),
+        "[\x0304404\x0f] Not Found - \x0303example.com\x0f"
+    );
+}
+
 fn format_wolfram(pods: &[WolframPod]) -> Vec<String> {
     pods.iter()
         .take(3)
@@ -408,17 +2512,513 @@ fn format_wolfram(pods: &[WolframPod]) -> Vec<String> {
         .collect()
 }
 
-fn parse_url(text: &str, scheme_required: bool) -> Result<Url, url::ParseError> {
-    match Url::parse(text) {
-        Ok(mut url) => {
-            if let Some("twitter.com") = url.host_str() {
-                let _ = url.set_host(Some("uk.unofficialbird.com"));
+/// Find the URLs in a message worth looking up, in order of appearance.
+pub fn extract_urls(content: &str, config: &UrlConfig, links: TwitterLinks) -> Vec<Url> {
+    extract_urls_counting(content, config, links).0
+}
+
+/// Find the URLs in a message worth looking up, and how many more there were
+/// beyond `max_per_message`
+fn extract_urls_counting(
+    content: &str,
+    config: &UrlConfig,
+    links: TwitterLinks,
+) -> (Vec<Url>, usize) {
+    let mut urls: Vec<_> = detect_urls(content, config)
+        .into_iter()
+        .filter(|url| !config.ignore_url_regex.is_match(url))
+        .filter_map(|url| parse_url(url, config))
+        .map(|url| rewrite_twitter(url, links))
+        .unique_by(|url| {
+            if config.ignore_fragments {
+                normalize_url(url)
+            } else {
+                url.clone()
             }
-            Ok(url)
-        },
-        Err(url::ParseError::RelativeUrlWithoutBase) if !scheme_required => {
-            Url::parse(&format!("http://{}", text))
+        })
+        .collect();
+    let excess = urls.len().saturating_sub(config.max_per_message as usize);
+    urls.truncate(config.max_per_message as usize);
+    (urls, excess)
+}
+
+#[test]
+fn test_extract_urls_counting() {
+    let config = UrlConfig {
+        max_per_message: 2,
+        ..UrlConfig::default()
+    };
+    let content = "https://example.com/a https://example.com/b https://example.com/c https://example.com/a https://example.org/d";
+    let (urls, excess) = extract_urls_counting(content, &config, TwitterLinks::Original);
+    assert_eq!(urls.len(), 2);
+    assert_eq!(excess, 2);
+    assert_eq!(
+        extract_urls_counting("https://example.com/a", &config, TwitterLinks::Original).1,
+        0
+    );
+}
+
+/// Find text that looks like a URL using the configured detectors
+fn detect_urls<'a>(content: &'a str, config: &UrlConfig) -> Vec<&'a str> {
+    lazy_static! {
+        static ref EXPLICIT_URL: Regex =
+            Regex::new(r#"\b([a-zA-Z][a-zA-Z0-9+.-]*)://[^\s<>"']+"#).unwrap();
+    }
+
+    let web = matches!(config.detector, UrlDetector::EggMode | UrlDetector::Both);
+    let strict = matches!(config.detector, UrlDetector::Regex | UrlDetector::Both);
+
+    // egg-mode only knows about the web, so pick out other allowed schemes ourselves
+    let explicit = EXPLICIT_URL
+        .captures_iter(content)
+        .filter(|cap| {
+            let scheme = cap[1].to_ascii_lowercase();
+            let is_web = scheme == "http" || scheme == "https";
+            (strict || !is_web) && config.allows_scheme(&scheme)
+        })
+        .map(|cap| cap.get(0).unwrap())
+        .map(|m| (m.start(), m.as_str()));
+
+    url_entities(content)
+        .into_iter()
+        .filter(|_| web)
+        .map(|url| (url.range.0, url.substr(content)))
+        .chain(explicit)
+        .sorted_by_key(|(start, _)| *start)
+        .map(|(_, url)| trim_url(url, config))
+        .collect()
+}
+
+/// Roughly what percentage of a message is made up of URLs
+fn url_share(content: &str, config: &UrlConfig) -> u8 {
+    let total = content.trim().chars().count();
+    if total == 0 {
+        return 0;
+    }
+
+    let urls: usize = detect_urls(content, config)
+        .into_iter()
+        .unique()
+        .map(|url| url.chars().count())
+        .sum();
+    (urls.min(total) * 100 / total) as u8
+}
+
+#[test]
+fn test_url_share() {
+    let config = UrlConfig::default();
+    assert_eq!(url_share(" https://example.com/ ", &config), 100);
+    assert_eq!(url_share("see https://example.com/", &config), 83);
+    assert_eq!(
+        url_share(
+            "I was reading https://example.com/ earlier and it was great",
+            &config
+        ),
+        33
+    );
+    assert_eq!(url_share("no links here", &config), 0);
+    assert_eq!(url_share("", &config), 0);
+}
+
+/// Run text through URL detection as `extract_urls` would, describing why
+/// nothing would be looked up if that's the case
+fn explain_url(text: &str, config: &UrlConfig, links: TwitterLinks) -> Result<Url, String> {
+    let found = match detect_urls(text, config).first() {
+        Some(found) => *found,
+        None => {
+            return Err(match Url::parse(text.trim()) {
+                Ok(url) if !config.allows_scheme(url.scheme()) => {
+                    format!("scheme {} is not in allowed_schemes", url.scheme())
+                }
+                _ => format!(
+                    "not detected as a URL by the {:?} detector",
+                    config.detector
+                ),
+            })
+        }
+    };
+
+    if config.ignore_url_regex.is_match(found) {
+        return Err(format!("{} matches ignore_url_regex", found));
+    }
+
+    let share = url_share(text, config);
+    if share < config.min_url_share_percent {
+        return Err(format!(
+            "URLs are only {}% of the message, under min_url_share_percent",
+            share
+        ));
+    }
+
+    parse_url(found, config)
+        .map(|url| rewrite_twitter(url, links))
+        .ok_or_else(|| match Url::parse(found) {
+            Ok(url) => format!("scheme {} is not in allowed_schemes", url.scheme()),
+            Err(url::ParseError::RelativeUrlWithoutBase) => {
+                format!("{} has no scheme and scheme_required is set", found)
+            }
+            Err(e) => format!("{} failed to parse: {}", found, e),
+        })
+}
+
+#[test]
+fn test_explain_url() {
+    let config = UrlConfig {
+        scheme_required: true,
+        ignore_url_regex: regex::RegexSet::new(["example\\.net"]).unwrap(),
+        ..UrlConfig::default()
+    };
+    assert_eq!(
+        explain_url(
+            "see https://example.com/a.",
+            &config,
+            TwitterLinks::Original
+        ),
+        Ok(Url::parse("https://example.com/a").unwrap())
+    );
+    assert_eq!(
+        explain_url("ftp://example.com/", &config, TwitterLinks::Original),
+        Err("scheme ftp is not in allowed_schemes".to_string())
+    );
+    assert_eq!(
+        explain_url("https://example.net/", &config, TwitterLinks::Original),
+        Err("https://example.net/ matches ignore_url_regex".to_string())
+    );
+    assert_eq!(
+        explain_url("example.com", &config, TwitterLinks::Original),
+        Err("example.com has no scheme and scheme_required is set".to_string())
+    );
+    assert!(explain_url("nothing here", &config, TwitterLinks::Original).is_err());
+}
+
+/// Strip the parts of a URL that don't affect what's fetched.
+fn normalize_url(url: &Url) -> Url {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    url
+}
+
+#[test]
+fn test_extract_urls_fragments() {
+    let config = UrlConfig::default();
+    let urls = extract_urls(
+        "see https://example.com/a#1 and https://example.com/a#2",
+        &config,
+        TwitterLinks::Original,
+    );
+    assert_eq!(urls, vec![Url::parse("https://example.com/a#1").unwrap()]);
+
+    let config = UrlConfig {
+        ignore_fragments: false,
+        ..UrlConfig::default()
+    };
+    let urls = extract_urls(
+        "see https://example.com/a#1 and https://example.com/a#2",
+        &config,
+        TwitterLinks::Original,
+    );
+    assert_eq!(urls.len(), 2);
+}
+
+#[test]
+fn test_extract_urls_other_schemes() {
+    let content = "gemini://example.org/a then https://example.com/ and ftp://example.net/";
+    let urls = extract_urls(content, &UrlConfig::default(), TwitterLinks::Original);
+    assert_eq!(urls, vec![Url::parse("https://example.com/").unwrap()]);
+
+    let config = UrlConfig {
+        allowed_schemes: vec!["https".to_string(), "gemini".to_string()],
+        ..UrlConfig::default()
+    };
+    let urls = extract_urls(content, &config, TwitterLinks::Original);
+    assert_eq!(
+        urls,
+        vec![
+            Url::parse("gemini://example.org/a").unwrap(),
+            Url::parse("https://example.com/").unwrap()
+        ]
+    );
+}
+
+/// Strip sentence punctuation and closing brackets or quotes a URL is likely
+/// to be followed by, leaving any that are balanced within the URL itself.
+fn trim_url<'a>(mut url: &'a str, config: &UrlConfig) -> &'a str {
+    let unbalanced =
+        |url: &str, open: char, close: char| url.matches(open).count() < url.matches(close).count();
+
+    loop {
+        let trimmed = match url.chars().last() {
+            Some('.' | ',' | ';' | ':' | '!' | '?') if config.strip_trailing_punctuation => {
+                &url[..url.len() - 1]
+            }
+            Some(')') if config.strip_unbalanced_delimiters && unbalanced(url, '(', ')') => {
+                &url[..url.len() - 1]
+            }
+            Some(']') if config.strip_unbalanced_delimiters && unbalanced(url, '[', ']') => {
+                &url[..url.len() - 1]
+            }
+            Some(q @ ('"' | '\'')) if config.strip_unbalanced_delimiters => {
+                if url.matches(q).count() % 2 == 1 {
+                    &url[..url.len() - 1]
+                } else {
+                    url
+                }
+            }
+            _ => url,
+        };
+
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+#[test]
+fn test_trim_url() {
+    let mut config = UrlConfig::default();
+    let wiki = "https://en.wikipedia.org/wiki/Rust_(programming_language)";
+    assert_eq!(trim_url(wiki, &config), wiki);
+    assert_eq!(trim_url(&format!("{}).", wiki), &config), wiki);
+    assert_eq!(
+        trim_url("https://example.com/a),", &config),
+        "https://example.com/a"
+    );
+    assert_eq!(
+        trim_url("https://example.com/a]", &config),
+        "https://example.com/a"
+    );
+    assert_eq!(
+        trim_url("https://example.com/[a]", &config),
+        "https://example.com/[a]"
+    );
+    assert_eq!(
+        trim_url("https://example.com/q\".", &config),
+        "https://example.com/q"
+    );
+    assert_eq!(
+        trim_url("https://example.com/q'", &config),
+        "https://example.com/q"
+    );
+    assert_eq!(
+        trim_url("https://example.com/...", &config),
+        "https://example.com/"
+    );
+
+    config.strip_unbalanced_delimiters = false;
+    assert_eq!(
+        trim_url("https://example.com/a),", &config),
+        "https://example.com/a)"
+    );
+
+    config.strip_trailing_punctuation = false;
+    assert_eq!(
+        trim_url("https://example.com/a),", &config),
+        "https://example.com/a),"
+    );
+}
+
+#[test]
+fn test_extract_urls_detectors() {
+    let content = "see https://example.com/a. or (https://example.com/b), example.org/c!";
+    let urls = |config: &UrlConfig| {
+        extract_urls(content, config, TwitterLinks::Original)
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+
+    let mut config = UrlConfig {
+        max_per_message: 10,
+        ..UrlConfig::default()
+    };
+    assert_eq!(
+        urls(&config),
+        vec![
+            "https://example.com/a",
+            "https://example.com/b",
+            "https://example.org/c"
+        ]
+    );
+
+    // The regex detector requires a scheme and knows nothing of brackets
+    config.detector = UrlDetector::Regex;
+    assert_eq!(
+        urls(&config),
+        vec!["https://example.com/a", "https://example.com/b"]
+    );
+
+    config.strip_trailing_punctuation = false;
+    config.strip_unbalanced_delimiters = false;
+    assert_eq!(
+        urls(&config),
+        vec!["https://example.com/a.", "https://example.com/b),"]
+    );
+
+    config.detector = UrlDetector::Both;
+    assert_eq!(urls(&config).len(), 5);
+}
+
+fn parse_url(text: &str, config: &UrlConfig) -> Option<Url> {
+    let mut url = match Url::parse(text) {
+        Ok(url) => url,
+        Err(url::ParseError::RelativeUrlWithoutBase) if !config.scheme_required => {
+            Url::parse(&format!("https://{}", text)).ok()?
         }
-        Err(e) => Err(e),
+        Err(_) => return None,
+    };
+
+    if config.require_https && url.scheme() == "http" {
+        url.set_scheme("https").ok()?;
     }
+
+    Some(url).filter(|url| config.allows_scheme(url.scheme()))
+}
+
+#[test]
+fn test_parse_url_schemes() {
+    let config = UrlConfig::default();
+    assert!(parse_url("file:///etc/passwd", &config).is_none());
+    assert!(parse_url("data:text/html,<title>hi</title>", &config).is_none());
+    assert!(parse_url("ftp://ftp.example.com/", &config).is_none());
+    assert!(parse_url("https://example.com/", &config).is_some());
+    assert!(parse_url("example.com/", &config).is_some());
+    assert!(parse_url("javascript:alert(1)", &config).is_none());
+
+    let config = UrlConfig {
+        allowed_schemes: vec!["ftp".to_string()],
+        ..UrlConfig::default()
+    };
+    assert!(parse_url("ftp://ftp.example.com/", &config).is_some());
+    assert!(parse_url("https://example.com/", &config).is_none());
+
+    let config = UrlConfig {
+        require_https: true,
+        ..UrlConfig::default()
+    };
+    assert_eq!(
+        parse_url("http://example.com:8080/a", &config).map(|u| u.to_string()),
+        Some("https://example.com:8080/a".to_string())
+    );
+    assert_eq!(
+        parse_url("HTTP://example.com/", &config).map(|u| u.to_string()),
+        Some("https://example.com/".to_string())
+    );
+}
+
+#[test]
+fn test_parse_url_guesses_https() {
+    let config = UrlConfig::default();
+    assert_eq!(
+        parse_url("example.com/", &config).map(|u| u.to_string()),
+        Some("https://example.com/".to_string())
+    );
+    // An explicit scheme is left alone
+    assert_eq!(
+        parse_url("http://example.com/", &config).map(|u| u.to_string()),
+        Some("http://example.com/".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_require_https_no_fallback() {
+    use tokio::io::AsyncReadExt;
+
+    // A cleartext server records how each connection starts
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0; 4];
+            let _ = stream.read_exact(&mut buf).await;
+            let _ = tx.send(buf);
+        }
+    });
+
+    let mut config = BotConfig::default();
+    config.url.require_https = true;
+    let url = parse_url(&format!("http://{}/", addr), &config.url).unwrap();
+    assert_eq!(url.scheme(), "https");
+
+    let log = Logger::root(slog::Discard, o!());
+    let handler = CommandHandler::new(log, ConfigMonitor::fixed(config));
+    let options = UrlOptions {
+        policy: UrlPolicy::Anywhere,
+        language: None,
+    };
+    let (res, _) = handler.spawn(BotCommand::Url(url, options)).unwrap();
+    assert!(res.await.unwrap().is_err());
+
+    // Every attempt was a TLS handshake, never a retry in the clear
+    drop(handler);
+    let mut attempts = 0;
+    while let Ok(Some(start)) = tokio::time::timeout(Duration::from_millis(50), rx.recv()).await {
+        assert_eq!(start[0], 0x16, "cleartext request {:?}", start);
+        attempts += 1;
+    }
+    assert!(attempts > 0);
+}
+
+/// Hosts serving Twitter links, all of which are rewritten alike
+const TWITTER_HOSTS: &[&str] = &[
+    "twitter.com",
+    "www.twitter.com",
+    "mobile.twitter.com",
+    "x.com",
+    "www.x.com",
+];
+
+/// Point Twitter links at a Nitter instance if the channel wants that
+fn rewrite_twitter(mut url: Url, links: TwitterLinks) -> Url {
+    if let TwitterLinks::Nitter(host) = links {
+        if url.host_str().is_some_and(|h| TWITTER_HOSTS.contains(&h)) {
+            let _ = url.set_host(Some(host));
+        }
+    }
+    url
+}
+
+#[test]
+fn test_extract_urls_twitter_links() {
+    let config = UrlConfig::default();
+    let nitter = TwitterLinks::Nitter("nitter.example");
+    for content in [
+        "https://twitter.com/rustlang/status/1",
+        "https://www.twitter.com/rustlang/status/1",
+        "https://mobile.twitter.com/rustlang/status/1",
+        "https://x.com/rustlang/status/1",
+        "https://www.x.com/rustlang/status/1",
+    ] {
+        assert_eq!(
+            extract_urls(content, &config, nitter),
+            vec![Url::parse("https://nitter.example/rustlang/status/1").unwrap()],
+            "{}",
+            content
+        );
+        assert_eq!(
+            extract_urls(content, &config, TwitterLinks::Original),
+            vec![Url::parse(content).unwrap()]
+        );
+        assert_eq!(
+            explain_url(content, &config, TwitterLinks::Original),
+            Ok(Url::parse(content).unwrap())
+        );
+    }
+
+    // Only the hosts themselves, not lookalikes or other subdomains
+    for content in ["https://nottwitter.com/a", "https://api.x.com/a"] {
+        assert_eq!(
+            extract_urls(content, &config, nitter),
+            vec![Url::parse(content).unwrap()]
+        );
+    }
+
+    // With no instance configured, links are left as they are
+    let twitter = TwitterConfig::default();
+    assert_eq!(twitter.links(), TwitterLinks::Original);
+    assert_eq!(
+        extract_urls("https://x.com/a", &config, twitter.links()),
+        vec![Url::parse("https://x.com/a").unwrap()]
+    );
 }
@@ -5,6 +5,9 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Serialize;
 
+/// Room for the text of a line, leaving space for the target and a short prefix
+pub const LINE_BYTES: usize = 380;
+
 #[derive(Debug, Clone, PartialEq, Hash, Serialize)]
 /// An IRC-safe string with stripped control codes, trimmed whitespace, and a reasonable length
 pub struct IrcString(String);
@@ -22,10 +25,6 @@ impl IrcString {
     pub fn trunc(&'_ self, max: usize) -> MaybeTruncated<'_> {
         truncate(&self.0, max)
     }
-
-    pub fn into_string(self) -> String {
-        self.0
-    }
 }
 
 impl std::ops::Deref for IrcString {
@@ -39,9 +38,16 @@ impl std::ops::Deref for IrcString {
 /// Collapse all whitespace, strip control codes and obvious combining character abuse,
 /// And truncate to a given size, appending a unicode ellipsis if appropriate.
 /// Will overshoot max_bytes by 3 because of that.
+///
+/// Anything repeating user input back to IRC should go through this, or
+/// `IrcString`, so it can't add its own formatting or break the line.
 pub fn sanitize(text: &str, max_bytes: usize) -> String {
     lazy_static! {
-        static ref CONTROL: Regex = Regex::new(r"\pC|(?:\pM{2})\pM+").unwrap();
+        // Colour codes are stripped along with their parameters, e.g. \x0304,01
+        static ref CONTROL: Regex = Regex::new(
+            r"\x03(?:\d{1,2}(?:,\d{1,2})?)?|\x04(?:[[:xdigit:]]{6}(?:,[[:xdigit:]]{6})?)?|\pC|(?:\pM{2})\pM+"
+        )
+        .unwrap();
     }
 
     let text = join(
@@ -66,6 +72,29 @@ fn vaguely_test_sanitize() {
     }
 }
 
+#[test]
+fn test_sanitize_echoed_input() {
+    assert_eq!(
+        sanitize("evil\r\nQUIT :bye \x0304red\x0f \x02bold\x02", 100),
+        "evil QUIT :bye red bold"
+    );
+    assert_eq!(
+        sanitize("\x034,12on blue\x03 \x04ff0000hex\x04 2\x03,", 100),
+        "on blue hex 2,"
+    );
+}
+
+/// Fold any line breaks into spaces, so a line we send can't be cut short or
+/// carry another command.  A backstop for text we've formatted ourselves,
+/// which keeps its colours.
+pub fn single_line(text: &str) -> std::borrow::Cow<'_, str> {
+    if text.contains(['\r', '\n', '\0']) {
+        text.replace(['\r', '\n', '\0'], " ").into()
+    } else {
+        text.into()
+    }
+}
+
 fn truncate(s: &'_ str, max_bytes: usize) -> MaybeTruncated<'_> {
     use unicode_segmentation::UnicodeSegmentation;
     s.grapheme_indices(true)
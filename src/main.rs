@@ -1,16 +1,25 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use clap::Parser;
 use futures::stream::FuturesUnordered;
-use slog::{crit, o, warn, Drain, Level, Logger};
+use itertools::Itertools;
+use slog::{crit, info, o, warn, Drain, Level, Logger};
 use tokio_stream::StreamExt;
 
+mod api;
+mod audit;
+mod caps;
 mod command;
 mod config;
+mod factoid;
+mod gemini;
 mod irc;
 mod irc_string;
 mod omdb;
+mod registry;
+mod schedule;
+mod tmdb;
 mod wolfram;
 mod youtube;
 
@@ -20,29 +29,107 @@ use crate::{command::*, config::*, irc::*};
 struct Args {
     #[clap(short, long, default_value = "annoirc.toml")]
     config: PathBuf,
+    /// Validate the configuration and exit, as is done before every reload
+    #[clap(long)]
+    check: bool,
+    /// Retry loading the configuration at startup this many times, e.g. while
+    /// waiting for a volume to be mounted
+    #[clap(long, default_value_t = 0)]
+    config_retries: u32,
+    /// Seconds to wait between attempts to load the configuration at startup
+    #[clap(long, default_value_t = 5)]
+    config_retry_delay: u64,
+    /// Look up a URL or command, e.g. ".imdb the matrix", print the result and
+    /// exit
+    #[clap(long, value_name = "TEXT")]
+    once: Option<String>,
+    /// With --once, print the result as JSON rather than as it would appear on
+    /// IRC
+    #[clap(long, requires = "once")]
+    json: bool,
+}
+
+/// Look up a single URL or command from the command line
+async fn once(args: &Args, text: &str) -> Result<()> {
+    let config = BotConfig::load(&args.config).await?;
+    let cmd = api::parse_lookup(text, &config).map_err(|e| anyhow!(e))?;
+
+    let log = Logger::root(slog::Discard, o!());
+    let handler = CommandHandler::new(log, ConfigMonitor::fixed(config.clone()));
+    let res = match handler.spawn(cmd) {
+        Some((res, _)) => res.await?,
+        None => return Err(anyhow!("lookup refused")),
+    };
+    let info = match &*res {
+        Ok(info) => info,
+        Err(e) => return Err(anyhow!("{:#}", e)),
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(info)?);
+    } else {
+        let locale = config.locale_for(None, "");
+        for line in format_response(info, &config, locale, irc_string::LINE_BYTES) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
 }
 
 async fn run(args: Args, log: Logger) -> Result<()> {
-    let mut config_update = ConfigMonitor::watch(log.clone(), &args.config).await?;
+    let mut config_update = ConfigMonitor::watch(
+        log.clone(),
+        &args.config,
+        args.config_retries,
+        Duration::from_secs(args.config_retry_delay),
+    )
+    .await?;
     let mut config = config_update.current();
 
     let handler = CommandHandler::new(log.clone(), config_update.clone());
+    let group_dedupe = GroupDedupe::default();
+    if let Some(addr) = config.api.listen {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        warn!(log, "api"; "listen" => %addr);
+        tokio::spawn(api::serve(
+            log.clone(),
+            listener,
+            config_update.clone(),
+            handler.clone(),
+        ));
+    }
+    let live = Connections::default();
+    tokio::spawn(schedule::run(
+        log.clone(),
+        config_update.clone(),
+        live.clone(),
+    ));
     let mut networks = std::collections::HashSet::<String>::new();
     let mut connections = FuturesUnordered::new();
     let mut active = true;
 
     loop {
         if active {
-            for netname in config.network.keys() {
-                if !networks.contains(netname) {
-                    networks.insert(netname.clone());
-                    connections.push(IrcTask::spawn(
-                        log.clone(),
-                        handler.clone(),
-                        config_update.clone(),
-                        netname.clone(),
-                    ));
+            let limit = config.max_networks.map_or(usize::MAX, |n| n as usize);
+            for netname in config.network.keys().sorted() {
+                if networks.contains(netname) {
+                    continue;
                 }
+
+                if networks.len() >= limit {
+                    info!(log, "network"; "status" => "waiting", "network" => netname, "max_networks" => limit);
+                    continue;
+                }
+
+                networks.insert(netname.clone());
+                connections.push(IrcTask::spawn(
+                    log.clone(),
+                    handler.clone(),
+                    config_update.clone(),
+                    group_dedupe.clone(),
+                    live.clone(),
+                    netname.clone(),
+                ));
             }
         }
 
@@ -69,6 +156,25 @@ async fn run(args: Args, log: Logger) -> Result<()> {
 async fn main() {
     let args = Args::parse();
 
+    if args.check {
+        match BotConfig::load(&args.config).await {
+            Ok(_) => println!("{}: OK", args.config.display()),
+            Err(e) => {
+                eprintln!("{}: {:#}", args.config.display(), e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(text) = &args.once {
+        if let Err(e) = once(&args, text).await {
+            eprintln!("{}: {:#}", text, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let ec = {
         let decorator = slog_term::TermDecorator::new().stdout().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
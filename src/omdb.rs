@@ -1,11 +1,15 @@
 use anyhow::Result;
-use omdb::Kind;
+pub use omdb::Kind;
+use serde::Serialize;
 
 use crate::irc_string::IrcString;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Movie {
     pub title: IrcString,
+    pub kind: Kind,
+    /// A range of years for series, e.g. "2016–2019", or "2016–" if ongoing
+    pub year: IrcString,
     pub plot: IrcString,
     pub rated: IrcString,
     pub released: IrcString,
@@ -16,12 +20,45 @@ pub struct Movie {
     pub imdb_votes: IrcString,
     pub imdb_id: IrcString,
     pub metascore: IrcString,
+    /// Other search results for the same query, when disambiguating
+    pub alternatives: Vec<Candidate>,
+}
+
+/// A search result, with much less detail than a full `Movie`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Candidate {
+    pub title: IrcString,
+    pub year: IrcString,
+    pub imdb_id: IrcString,
+    pub kind: Kind,
+}
+
+pub fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Movie => "movie",
+        Kind::Series => "series",
+        Kind::Episode => "episode",
+        Kind::Game => "game",
+    }
+}
+
+impl From<omdb::SearchResultsMovie> for Candidate {
+    fn from(movie: omdb::SearchResultsMovie) -> Self {
+        Candidate {
+            title: movie.title.into(),
+            year: movie.year.into(),
+            imdb_id: movie.imdb_id.into(),
+            kind: movie.kind,
+        }
+    }
 }
 
 impl From<omdb::Movie> for Movie {
     fn from(movie: omdb::Movie) -> Self {
         Movie {
             title: movie.title.into(),
+            kind: movie.kind,
+            year: movie.year.into(),
             plot: movie.plot.into(),
             rated: movie.rated.into(),
             released: movie.released.into(),
@@ -32,6 +69,7 @@ impl From<omdb::Movie> for Movie {
             imdb_votes: movie.imdb_votes.into(),
             imdb_id: movie.imdb_id.into(),
             metascore: movie.metascore.into(),
+            alternatives: vec![],
         }
     }
 }
@@ -40,15 +78,67 @@ pub async fn imdb_id(id: &str, key: &str) -> Result<Movie> {
     Ok(omdb::imdb_id(id).apikey(key).get().await?.into())
 }
 
+fn parse_kind(kind: &str) -> Option<Kind> {
+    match kind {
+        "Movie" => Some(Kind::Movie),
+        "Series" => Some(Kind::Series),
+        "Episode" => Some(Kind::Episode),
+        "Game" => Some(Kind::Game),
+        _ => None,
+    }
+}
+
 // king as an omdb::Kind would be nicer, but it lacks appropriate derives
 pub async fn search(query: &str, kind: &str, key: &str) -> Result<Movie> {
     let mut search = omdb::title(query);
-    match kind {
-        "Movie" => search.kind(Kind::Movie),
-        "Series" => search.kind(Kind::Series),
-        "Episode" => search.kind(Kind::Episode),
-        "Game" => search.kind(Kind::Game),
-        _ => &search,
-    };
+    if let Some(kind) = parse_kind(kind) {
+        search.kind(kind);
+    }
     Ok(search.apikey(key).get().await?.into())
 }
+
+/// All titles matching a query, in OMDB's order of relevance
+pub async fn candidates(query: &str, kind: &str, key: &str) -> Result<Vec<Candidate>> {
+    let mut search = omdb::search(query);
+    if let Some(kind) = parse_kind(kind) {
+        search.kind(kind);
+    }
+    Ok(search
+        .apikey(key)
+        .get()
+        .await?
+        .results
+        .into_iter()
+        .map(Candidate::from)
+        .collect())
+}
+
+/// Search for a title, noting any other candidates matching the query
+pub async fn disambiguate(query: &str, kind: &str, key: &str) -> Result<Movie> {
+    let (movie, candidates) =
+        futures::join!(search(query, kind, key), candidates(query, kind, key));
+
+    let mut movie = movie?;
+    movie.alternatives = candidates
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| c.imdb_id != movie.imdb_id)
+        .collect();
+    Ok(movie)
+}
+
+/// Whether a query looks like an IMDb title ID, e.g. tt0133093
+pub fn is_imdb_id(query: &str) -> bool {
+    query
+        .strip_prefix("tt")
+        .is_some_and(|n| n.len() >= 7 && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+#[test]
+fn test_is_imdb_id() {
+    assert!(is_imdb_id("tt0133093"));
+    assert!(is_imdb_id("tt10872600"));
+    assert!(!is_imdb_id("tt123"));
+    assert!(!is_imdb_id("the matrix"));
+    assert!(!is_imdb_id("tt0133093x"));
+}
@@ -0,0 +1,342 @@
+use crate::config::{BotConfig, CommandWithUrls};
+
+/// What a chat command does once matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// List recently previewed URLs in the channel
+    LastUrl,
+    /// Look up a URL explicitly
+    Title,
+    /// Search OMDB, restricted to the given kind of title
+    Omdb(&'static str),
+    /// List OMDB titles matching a search, to pick from with `.imdb <n>`
+    OmdbSearch,
+    /// Query WolframAlpha
+    Wolfram,
+    /// Explain why a URL would or wouldn't be previewed
+    Why,
+    /// Report bot-wide counters
+    Stats,
+    /// Remember a factoid for the channel
+    Learn,
+    /// Recall a factoid
+    WhatIs,
+    /// Forget a factoid
+    Forget,
+    /// Send a message to a configured set of channels across networks
+    Broadcast,
+    /// Restore a channel's rate limit allowance after a legitimate burst
+    Unlimit,
+}
+
+impl Action {
+    /// The kind of lookup the action runs, as limited by
+    /// `command.channels_per_kind`
+    pub fn kind(&self) -> Option<&'static str> {
+        match self {
+            Self::Title => Some("url"),
+            Self::Omdb(_) | Self::OmdbSearch => Some("omdb"),
+            Self::Wolfram => Some("wolfram"),
+            _ => None,
+        }
+    }
+}
+
+/// A chat command, its aliases, and the conditions under which it's available
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub names: &'static [&'static str],
+    pub action: Action,
+    pub needs_args: bool,
+    /// Only available to the network's admins
+    pub admin: bool,
+    /// Cheap and informational, so admins aren't held to the channel's rate
+    /// limit for it
+    pub diagnostic: bool,
+    pub enabled: fn(&BotConfig) -> bool,
+}
+
+impl CommandSpec {
+    /// Whether only admins may use the command, either always or as configured
+    pub fn admin_only(&self, config: &BotConfig) -> bool {
+        self.admin
+            || config
+                .command
+                .admin_only
+                .iter()
+                .any(|name| self.names.contains(&name.as_str()))
+    }
+}
+
+fn always(_: &BotConfig) -> bool {
+    true
+}
+
+fn omdb(config: &BotConfig) -> bool {
+    config.omdb.api_key.is_some()
+}
+
+fn factoids(config: &BotConfig) -> bool {
+    config.factoid.enabled
+}
+
+fn broadcast(config: &BotConfig) -> bool {
+    !config.broadcast.is_empty()
+}
+
+fn wolfram(config: &BotConfig) -> bool {
+    config.wolfram.app_id.is_some()
+}
+
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        names: &["lasturl"],
+        action: Action::LastUrl,
+        needs_args: false,
+        admin: false,
+        diagnostic: false,
+        enabled: always,
+    },
+    CommandSpec {
+        names: &["title", "url"],
+        action: Action::Title,
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: always,
+    },
+    CommandSpec {
+        names: &["imdb", "omdb"],
+        action: Action::Omdb("Any"),
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: omdb,
+    },
+    CommandSpec {
+        names: &["find", "imdbsearch"],
+        action: Action::OmdbSearch,
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: omdb,
+    },
+    CommandSpec {
+        names: &["film", "movie"],
+        action: Action::Omdb("Movie"),
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: omdb,
+    },
+    CommandSpec {
+        names: &["show", "series", "tv"],
+        action: Action::Omdb("Series"),
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: omdb,
+    },
+    CommandSpec {
+        names: &["ep", "episode"],
+        action: Action::Omdb("Episode"),
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: omdb,
+    },
+    CommandSpec {
+        names: &["game"],
+        action: Action::Omdb("Game"),
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: omdb,
+    },
+    CommandSpec {
+        names: &["wolfram", "calc"],
+        action: Action::Wolfram,
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: wolfram,
+    },
+    CommandSpec {
+        names: &["why"],
+        action: Action::Why,
+        needs_args: true,
+        admin: true,
+        diagnostic: true,
+        enabled: always,
+    },
+    CommandSpec {
+        names: &["stats"],
+        action: Action::Stats,
+        needs_args: false,
+        admin: false,
+        diagnostic: true,
+        enabled: always,
+    },
+    CommandSpec {
+        names: &["learn"],
+        action: Action::Learn,
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: factoids,
+    },
+    CommandSpec {
+        names: &["whatis"],
+        action: Action::WhatIs,
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: factoids,
+    },
+    CommandSpec {
+        names: &["forget"],
+        action: Action::Forget,
+        needs_args: true,
+        admin: false,
+        diagnostic: false,
+        enabled: factoids,
+    },
+    CommandSpec {
+        names: &["broadcast"],
+        action: Action::Broadcast,
+        needs_args: true,
+        admin: true,
+        diagnostic: false,
+        enabled: broadcast,
+    },
+    CommandSpec {
+        names: &["unlimit"],
+        action: Action::Unlimit,
+        needs_args: true,
+        admin: true,
+        diagnostic: true,
+        enabled: always,
+    },
+];
+
+/// Find an enabled command by name or alias
+pub fn lookup(name: &str, config: &BotConfig) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.names.contains(&name))
+        .filter(|spec| (spec.enabled)(config))
+}
+
+/// Split a message bearing the channel's command prefix into a recognised
+/// command and its arguments
+pub fn parse(
+    content: &str,
+    prefix: &str,
+    config: &BotConfig,
+) -> Option<(&'static CommandSpec, String)> {
+    let rest = content.strip_prefix(prefix)?;
+    let mut split = rest.split_ascii_whitespace();
+    let name = split.next()?.to_ascii_lowercase();
+    let spec = lookup(&name, config)?;
+    Some((spec, itertools::join(split, " ")))
+}
+
+/// The key of a factoid being recalled with a bare `.<key>`, if enabled
+pub fn factoid_key<'a>(content: &'a str, prefix: &str, config: &BotConfig) -> Option<&'a str> {
+    let key = content.strip_prefix(prefix)?.trim_end();
+    Some(key).filter(|key| {
+        config.factoid.enabled
+            && !key.is_empty()
+            && !key.contains(char::is_whitespace)
+            && lookup(&key.to_ascii_lowercase(), config).is_none()
+    })
+}
+
+#[test]
+fn test_factoid_key() {
+    let mut config = BotConfig::default();
+    assert_eq!(factoid_key(".rust", ".", &config), None);
+    config.factoid.enabled = true;
+    assert_eq!(factoid_key(".rust ", ".", &config), Some("rust"));
+    assert_eq!(factoid_key(".rust is great", ".", &config), None);
+    assert_eq!(factoid_key(".lasturl", ".", &config), None);
+    assert_eq!(factoid_key(".", ".", &config), None);
+    assert_eq!(factoid_key("rust", ".", &config), None);
+}
+
+/// Whether URLs in a message should still be previewed, given any command in it
+pub fn previews_urls(content: &str, prefix: &str, config: &BotConfig) -> bool {
+    match config.command.with_urls {
+        CommandWithUrls::Both => true,
+        CommandWithUrls::CommandOrUrls => parse(content, prefix, config).is_none(),
+    }
+}
+
+#[test]
+fn test_parse_ascii_names() {
+    let mut config = BotConfig::default();
+    config.omdb.api_key = Some("key".to_string());
+    assert!(parse(".IMDB matrix", ".", &config).is_some());
+    // Turkish dotted and dotless capital I look like I but aren't
+    assert!(parse(".\u{130}MDB matrix", ".", &config).is_none());
+    assert!(parse(".\u{131}mdb matrix", ".", &config).is_none());
+}
+
+#[test]
+fn test_previews_urls() {
+    let mut config = BotConfig::default();
+    assert!(parse(".foo check https://example.com", ".", &config).is_none());
+    assert!(previews_urls(
+        ".foo check https://example.com",
+        ".",
+        &config
+    ));
+    assert!(!previews_urls(".lasturl https://example.com", ".", &config));
+
+    let (spec, args) = parse(".LastUrl 2 https://example.com", ".", &config).unwrap();
+    assert_eq!(spec.action, Action::LastUrl);
+    assert_eq!(args, "2 https://example.com");
+
+    // Only the channel's own prefix makes a command
+    assert!(previews_urls(".lasturl https://example.com", "!", &config));
+    assert!(!previews_urls("!lasturl https://example.com", "!", &config));
+
+    config.command.with_urls = CommandWithUrls::Both;
+    assert!(previews_urls(
+        ".foo check https://example.com",
+        ".",
+        &config
+    ));
+    assert!(previews_urls(".lasturl https://example.com", ".", &config));
+}
+
+#[test]
+fn test_lookup() {
+    let mut config = BotConfig::default();
+    assert_eq!(lookup("lasturl", &config).unwrap().action, Action::LastUrl);
+    assert!(lookup("tv", &config).is_none());
+    assert!(lookup("calc", &config).is_none());
+    assert!(lookup("nonsense", &config).is_none());
+
+    config.omdb.api_key = Some("key".to_string());
+    config.wolfram.app_id = Some("id".to_string());
+    assert_eq!(
+        lookup("tv", &config).unwrap().action,
+        Action::Omdb("Series")
+    );
+    assert_eq!(lookup("calc", &config).unwrap().action, Action::Wolfram);
+
+    // Kinds match those of the lookups the actions run
+    assert_eq!(lookup("title", &config).unwrap().action.kind(), Some("url"));
+    assert_eq!(lookup("tv", &config).unwrap().action.kind(), Some("omdb"));
+    assert_eq!(
+        lookup("calc", &config).unwrap().action.kind(),
+        Some("wolfram")
+    );
+    assert_eq!(lookup("lasturl", &config).unwrap().action.kind(), None);
+
+    assert!(lookup("why", &config).unwrap().admin_only(&config));
+    assert!(!lookup("stats", &config).unwrap().admin_only(&config));
+    config.command.admin_only = vec!["stats".to_string()];
+    assert!(lookup("stats", &config).unwrap().admin_only(&config));
+}
@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use slog::{info, warn, Logger};
+
+use crate::{
+    config::{ConfigMonitor, ScheduleConfig},
+    irc::Connections,
+};
+
+/// When the next of any schedule is due after a given time
+fn next_due(schedules: &[ScheduleConfig], after: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+    schedules
+        .iter()
+        .filter_map(|s| s.cron.after(after).next())
+        .min()
+}
+
+/// Schedules due at least once since the last check, each only once however
+/// many times it was missed
+fn due<'a>(
+    schedules: &'a [ScheduleConfig],
+    since: &'a DateTime<Utc>,
+    now: &'a DateTime<Utc>,
+) -> impl Iterator<Item = &'a ScheduleConfig> {
+    schedules
+        .iter()
+        .filter(move |s| s.cron.after(since).next().is_some_and(|at| at <= *now))
+}
+
+#[test]
+fn test_due() {
+    use chrono::TimeZone;
+
+    let config: crate::config::BotConfig = toml::from_str(
+        r##"
+        [[schedule]]
+        network = "one"
+        channel = "#chan"
+        cron = "0 0 9 * * Mon"
+        message = "Weekly meeting in {channel}"
+
+        [[schedule]]
+        network = "one"
+        channel = "#chan"
+        cron = "0 0 * * * * *"
+        message = "Hourly"
+        "##,
+    )
+    .unwrap();
+    let schedules = &config.schedule;
+
+    // 2024-01-01 was a Monday
+    let since = Utc.with_ymd_and_hms(2024, 1, 1, 8, 30, 0).unwrap();
+    assert_eq!(
+        next_due(schedules, &since),
+        Some(Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap())
+    );
+
+    let now = Utc.with_ymd_and_hms(2024, 1, 1, 8, 59, 59).unwrap();
+    assert_eq!(due(schedules, &since, &now).count(), 0);
+
+    let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+    assert_eq!(due(schedules, &since, &now).count(), 2);
+
+    let since = now;
+    let now = Utc.with_ymd_and_hms(2024, 1, 1, 13, 0, 0).unwrap();
+    let due: Vec<_> = due(schedules, &since, &now).collect();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].message, "Hourly");
+}
+
+/// Post scheduled messages to their channels for as long as we're configured,
+/// skipping any whose network isn't connected at the time
+pub async fn run(log: Logger, mut config: ConfigMonitor, connections: Connections) {
+    let mut conf = config.current();
+    let mut since = Utc::now();
+
+    loop {
+        let wait = next_due(&conf.schedule, &since)
+            .map(|at| (at - Utc::now()).to_std().unwrap_or_default());
+
+        tokio::select! {
+            newconf = config.next() => match newconf {
+                Some(newconf) => conf = newconf,
+                None => break,
+            },
+            _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                let now = Utc::now();
+                for schedule in due(&conf.schedule, &since, &now) {
+                    let message = schedule.message.replace("{channel}", &schedule.channel);
+                    match connections.get(&schedule.network) {
+                        Some(output) => {
+                            info!(log, "schedule"; "network" => &schedule.network, "channel" => &schedule.channel);
+                            if let Err(e) = output.privmsg(&schedule.channel, message) {
+                                warn!(log, "schedule"; "network" => &schedule.network, "error" => %e);
+                            }
+                        }
+                        None => {
+                            warn!(log, "schedule"; "status" => "skipped", "reason" => "not connected", "network" => &schedule.network, "channel" => &schedule.channel);
+                        }
+                    }
+                }
+                since = now;
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{command::check_status, config::TmdbConfig, omdb::Movie};
+
+#[derive(Debug, Deserialize)]
+struct FindResponse {
+    #[serde(default)]
+    movie_results: Vec<TmdbTitle>,
+    #[serde(default)]
+    tv_results: Vec<TmdbTitle>,
+    #[serde(default)]
+    tv_episode_results: Vec<TmdbTitle>,
+}
+
+/// The parts of a TMDb movie, series or episode we can use.  Series and
+/// episodes have a `name` rather than a `title`.
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct TmdbTitle {
+    #[serde(alias = "name")]
+    title: Option<String>,
+    #[serde(default)]
+    overview: String,
+}
+
+/// Find the TMDb entry for an IMDb title ID, in the configured language
+pub async fn find(imdb_id: &str, config: &TmdbConfig) -> Result<Option<TmdbTitle>> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(format!("https://api.themoviedb.org/3/find/{}", imdb_id))
+        .query(&[
+            ("api_key", config.api_key.as_deref().unwrap_or_default()),
+            ("external_source", "imdb_id"),
+            ("language", config.language.as_deref().unwrap_or("en-US")),
+        ])
+        .send()
+        .await?;
+    check_status(response.status(), response.headers())?;
+    Ok(first_result(response.json().await?))
+}
+
+fn first_result(response: FindResponse) -> Option<TmdbTitle> {
+    response
+        .movie_results
+        .into_iter()
+        .chain(response.tv_results)
+        .chain(response.tv_episode_results)
+        .next()
+}
+
+/// OMDb's placeholder for a field it has nothing for
+fn missing(value: &str) -> bool {
+    value.is_empty() || value == "N/A"
+}
+
+/// Whether OMDb left out anything TMDb might have
+pub fn incomplete(movie: &Movie) -> bool {
+    missing(&movie.plot)
+}
+
+/// Fill gaps in OMDb's details from TMDb's, or if `prefer` is set, replace
+/// OMDb's title and plot with TMDb's, which may be localised
+pub fn merge(mut movie: Movie, tmdb: TmdbTitle, prefer: bool) -> Movie {
+    if let Some(title) = tmdb.title.filter(|t| prefer && !t.is_empty()) {
+        movie.title = title.into();
+    }
+    if !tmdb.overview.is_empty() && (prefer || missing(&movie.plot)) {
+        movie.plot = tmdb.overview.into();
+    }
+    movie
+}
+
+#[test]
+fn test_merge() {
+    let response: FindResponse = serde_json::from_str(
+        r#"{
+            "movie_results": [],
+            "tv_results": [{
+                "id": 66732,
+                "name": "Stranger Things",
+                "overview": "Als ein Junge verschwindet...",
+                "first_air_date": "2016-07-15"
+            }],
+            "tv_episode_results": []
+        }"#,
+    )
+    .unwrap();
+    let tmdb = first_result(response).unwrap();
+    assert_eq!(tmdb.title.as_deref(), Some("Stranger Things"));
+
+    let movie = Movie {
+        title: "Stranger Things".into(),
+        kind: crate::omdb::Kind::Series,
+        year: "2016–2025".into(),
+        plot: "N/A".into(),
+        rated: "".into(),
+        released: "".into(),
+        runtime: "".into(),
+        genre: "".into(),
+        director: "".into(),
+        imdb_rating: "".into(),
+        imdb_votes: "".into(),
+        imdb_id: "tt4574334".into(),
+        metascore: "".into(),
+        alternatives: vec![],
+    };
+    assert!(incomplete(&movie));
+
+    let merged = merge(movie.clone(), tmdb, false);
+    assert_eq!(&*merged.plot, "Als ein Junge verschwindet...");
+    assert!(!incomplete(&merged));
+
+    let localised = TmdbTitle {
+        title: Some("Stranger Things – Die Serie".to_string()),
+        overview: "Vier Freunde suchen...".to_string(),
+    };
+    let merged = merge(merged, localised, false);
+    assert_eq!(&*merged.title, "Stranger Things");
+    assert_eq!(&*merged.plot, "Als ein Junge verschwindet...");
+
+    let localised = TmdbTitle {
+        title: Some("Stranger Things – Die Serie".to_string()),
+        overview: "".to_string(),
+    };
+    let merged = merge(merged, localised, true);
+    assert_eq!(&*merged.title, "Stranger Things – Die Serie");
+    assert_eq!(&*merged.plot, "Als ein Junge verschwindet...");
+
+    let empty: FindResponse = serde_json::from_str("{}").unwrap();
+    assert_eq!(first_result(empty), None);
+}
@@ -1,7 +1,9 @@
+use std::fmt;
+
 use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::irc_string::IrcString;
+use crate::{command::check_status, config::WolframConfig, irc_string::IrcString};
 
 #[derive(Debug, Deserialize, PartialEq)]
 struct Response {
@@ -12,13 +14,36 @@ struct Response {
 struct QueryResult {
     success: bool,
     error: bool,
+    #[serde(default)]
     pods: Vec<Pod>,
+    didyoumeans: Option<DidYouMeans>,
+}
+
+/// Wolfram gives a single suggestion as an object, and several as an array
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum DidYouMeans {
+    One(DidYouMean),
+    Many(Vec<DidYouMean>),
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct DidYouMean {
+    val: String,
+}
+
+impl DidYouMeans {
+    fn first(self) -> Option<String> {
+        match self {
+            Self::One(suggestion) => Some(suggestion.val),
+            Self::Many(suggestions) => suggestions.into_iter().next().map(|s| s.val),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
 struct Pod {
     title: String,
-    id: String,
     error: bool,
     subpods: Vec<Subpod>,
 }
@@ -28,19 +53,34 @@ struct Subpod {
     plaintext: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+/// Wolfram couldn't make sense of a query, perhaps with a suggestion
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uninterpreted(pub Option<IrcString>);
+
+impl fmt::Display for Uninterpreted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Wolfram couldn't interpret that")?;
+        if let Some(suggestion) = &self.0 {
+            write!(f, ", did you mean: {}?", suggestion.trunc(100))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Uninterpreted {}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct WolframPod {
-    pub id: String,
     pub title: IrcString,
     pub values: Vec<IrcString>,
 }
 
-pub async fn wolfram_query(query: &str, app_id: &str) -> Result<Vec<WolframPod>> {
+pub async fn wolfram_query(query: &str, config: &WolframConfig) -> Result<Vec<WolframPod>> {
     let client = reqwest::Client::new();
-    let response = client
+    let mut response = client
         .get("http://api.wolframalpha.com/v2/query")
         .query(&[
-            ("appid", app_id),
+            ("appid", config.app_id.as_deref().unwrap_or_default()),
             ("input", query),
             ("podtimeout", "0.5"),
             ("format", "plaintext"),
@@ -48,16 +88,40 @@ pub async fn wolfram_query(query: &str, app_id: &str) -> Result<Vec<WolframPod>>
             ("output", "json"),
         ])
         .send()
-        .await?
-        .json::<Response>()
         .await?;
+    check_status(response.status(), response.headers())?;
+
+    let limit = config.max_kb as usize * 1024;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() > limit {
+            return Err(anyhow!("Response too large"));
+        }
+    }
+
+    parse_pods(&body, config.min_pods, config.show_suggestions)
+}
+
+fn parse_pods(body: &[u8], min_pods: usize, suggest: bool) -> Result<Vec<WolframPod>> {
+    let response = serde_json::from_slice::<Response>(body)?;
+
+    if !response.queryresult.success && !response.queryresult.error {
+        let suggestion = response
+            .queryresult
+            .didyoumeans
+            .filter(|_| suggest)
+            .and_then(DidYouMeans::first)
+            .map(IrcString::from)
+            .filter(|s| !s.is_empty());
+        return Err(Uninterpreted(suggestion).into());
+    }
 
     let pods: Vec<WolframPod> = response
         .queryresult
         .pods
         .into_iter()
         .map(|pod| WolframPod {
-            id: pod.id,
             title: pod.title.into(),
             values: pod
                 .subpods
@@ -70,9 +134,66 @@ pub async fn wolfram_query(query: &str, app_id: &str) -> Result<Vec<WolframPod>>
         .filter(|item| !item.values.is_empty())
         .collect();
 
-    if pods.len() > 1 {
+    if !pods.is_empty() && pods.len() >= min_pods {
         Ok(pods)
     } else {
         Err(anyhow!("No response pod"))
     }
 }
+
+#[test]
+fn test_parse_pods() {
+    let single = br#"{"queryresult": {"success": true, "error": false, "pods": [
+        {"title": "Result", "id": "Result", "error": false,
+         "subpods": [{"plaintext": "yes"}]}
+    ]}}"#;
+    let pods = parse_pods(single, 1, false).unwrap();
+    assert_eq!(pods.len(), 1);
+    assert_eq!(&*pods[0].values[0], "yes");
+    assert!(parse_pods(single, 2, false).is_err());
+
+    let multi = br#"{"queryresult": {"success": true, "error": false, "pods": [
+        {"title": "Input interpretation", "id": "Input", "error": false,
+         "subpods": [{"plaintext": "1 + 1"}]},
+        {"title": "Result", "id": "Result", "error": false,
+         "subpods": [{"plaintext": "2"}]},
+        {"title": "Plot", "id": "Plot", "error": false,
+         "subpods": [{"plaintext": ""}]}
+    ]}}"#;
+    let pods = parse_pods(multi, 1, false).unwrap();
+    assert_eq!(pods.len(), 2);
+    assert_eq!(&*pods[1].title, "Result");
+
+    let empty = br#"{"queryresult": {"success": true, "error": false, "pods": []}}"#;
+    assert!(parse_pods(empty, 0, false).is_err());
+}
+
+#[test]
+fn test_parse_uninterpreted() {
+    let uninterpreted = |body: &[u8], suggest| {
+        parse_pods(body, 1, suggest)
+            .unwrap_err()
+            .downcast::<Uninterpreted>()
+            .unwrap()
+    };
+
+    let bare = br#"{"queryresult": {"success": false, "error": false, "numpods": 0}}"#;
+    assert_eq!(uninterpreted(bare, true), Uninterpreted(None));
+    assert_eq!(
+        uninterpreted(bare, true).to_string(),
+        "Wolfram couldn't interpret that"
+    );
+
+    let one = br#"{"queryresult": {"success": false, "error": false, "numpods": 0,
+        "didyoumeans": {"score": "0.4", "level": "medium", "val": "pi squared"}}}"#;
+    assert_eq!(uninterpreted(one, false), Uninterpreted(None));
+    assert_eq!(
+        uninterpreted(one, true).to_string(),
+        "Wolfram couldn't interpret that, did you mean: pi squared?"
+    );
+
+    let many = br#"{"queryresult": {"success": false, "error": false, "numpods": 0,
+        "didyoumeans": [{"score": "0.5", "level": "medium", "val": "sun"},
+                        {"score": "0.2", "level": "low", "val": "son"}]}}"#;
+    assert_eq!(uninterpreted(many, true), Uninterpreted(Some("sun".into())));
+}
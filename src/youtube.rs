@@ -3,23 +3,28 @@ use std::time::Duration;
 use anyhow::{anyhow, Result};
 use chrono::{offset, DateTime};
 use iso8601_duration::Duration as IsoDuration;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 use url::Url;
 
-use crate::{config::YouTubeConfig, irc_string::IrcString};
+use crate::{command::check_status, config::YouTubeConfig, irc_string::IrcString};
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct YouTube {
     pub id: IrcString,
     pub title: IrcString,       // items[0]/snippet/title
     pub description: IrcString, // items[0]/snippet/description
     pub published_at: Option<DateTime<offset::FixedOffset>>, // items[0]/snippet/published_at
+    #[serde(serialize_with = "serialize_secs")]
     pub duration: Duration,     // items[0]/contentDetails/duration
     pub channel: IrcString,     // items[0]/snippet/channelTitle
     pub views: u64,             // items[0]/statistics/viewCount
     pub likes: u64,             // items[0]/statistics/likeCount
 }
 
+fn serialize_secs<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(duration.as_secs())
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct YouTubeResponse {
@@ -38,8 +43,6 @@ struct YouTubeItem {
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct YouTubeSnippet {
-    title: String,
-    description: String,
     channel_title: String,
     published_at: String,
     localized: YouTubeLocalised,
@@ -150,20 +153,28 @@ fn test_extract_youtube_id() {
     );
 }
 
-pub async fn youtube_lookup(id: &str, config: &YouTubeConfig) -> Result<YouTube> {
+/// Look up a video, with its details in `language` if given, or youtube.lang
+pub async fn youtube_lookup(
+    id: &str,
+    config: &YouTubeConfig,
+    language: Option<&str>,
+) -> Result<YouTube> {
     let client = reqwest::Client::new();
-    let mut response = client
+    let response = client
         .get("https://www.googleapis.com/youtube/v3/videos")
         .query(&[
             ("id", id),
             ("key", &config.api_key.clone().unwrap_or_default()),
-            ("hl", &config.lang.clone().unwrap_or_default()),
+            (
+                "hl",
+                language.or(config.lang.as_deref()).unwrap_or_default(),
+            ),
             ("part", "snippet,contentDetails,statistics"),
         ])
         .send()
-        .await?
-        .json::<YouTubeResponse>()
         .await?;
+    check_status(response.status(), response.headers())?;
+    let mut response = response.json::<YouTubeResponse>().await?;
 
     if let Some(item) = response.items.pop() {
         Ok(item.into())